@@ -1,5 +1,4 @@
 use rusqlite::{Connection, Result as SqlResult};
-use std::path::Path;
 
 pub const DATABASE_FILE: &str = "pipeline.db";
 
@@ -18,11 +17,27 @@ pub fn init_database() -> SqlResult<()> {
             end_time DATETIME,
             duration_seconds INTEGER,
             current_job_index INTEGER DEFAULT 0,
-            total_jobs INTEGER NOT NULL
+            total_jobs INTEGER NOT NULL,
+            commit_sha TEXT,
+            pipeline_config TEXT,
+            parent_run_id TEXT,
+            attempt INTEGER NOT NULL DEFAULT 1,
+            pinned INTEGER NOT NULL DEFAULT 0,
+            target TEXT,
+            parameters TEXT NOT NULL DEFAULT '{}'
         )",
         [],
     )?;
 
+    // Columns added after the first release
+    add_column_if_missing(&conn, "pipeline_runs", "commit_sha", "TEXT")?;
+    add_column_if_missing(&conn, "pipeline_runs", "pipeline_config", "TEXT")?;
+    add_column_if_missing(&conn, "pipeline_runs", "parent_run_id", "TEXT")?;
+    add_column_if_missing(&conn, "pipeline_runs", "attempt", "INTEGER NOT NULL DEFAULT 1")?;
+    add_column_if_missing(&conn, "pipeline_runs", "pinned", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(&conn, "pipeline_runs", "target", "TEXT")?;
+    add_column_if_missing(&conn, "pipeline_runs", "parameters", "TEXT NOT NULL DEFAULT '{}'")?;
+
     // Create job_runs table with job_index for ordering
     conn.execute(
         "CREATE TABLE IF NOT EXISTS job_runs (
@@ -35,11 +50,21 @@ pub fn init_database() -> SqlResult<()> {
             end_time DATETIME,
             duration_seconds INTEGER,
             output TEXT,
+            attempt INTEGER NOT NULL DEFAULT 1,
+            worker_id TEXT,
+            queue_reason TEXT,
+            lease_expires_at DATETIME,
             FOREIGN KEY(pipeline_run_id) REFERENCES pipeline_runs(id)
         )",
         [],
     )?;
 
+    add_column_if_missing(&conn, "job_runs", "attempt", "INTEGER NOT NULL DEFAULT 1")?;
+    add_column_if_missing(&conn, "job_runs", "worker_id", "TEXT")?;
+    add_column_if_missing(&conn, "job_runs", "queue_reason", "TEXT")?;
+    add_column_if_missing(&conn, "job_runs", "lease_expires_at", "DATETIME")?;
+    add_column_if_missing(&conn, "job_runs", "log_streamed", "INTEGER NOT NULL DEFAULT 0")?;
+
     // Create artifacts table for job outputs
     conn.execute(
         "CREATE TABLE IF NOT EXISTS job_artifacts (
@@ -48,6 +73,84 @@ pub fn init_database() -> SqlResult<()> {
             name TEXT NOT NULL,
             content TEXT NOT NULL,
             created_at DATETIME NOT NULL,
+            sha256 TEXT,
+            size INTEGER,
+            media_type TEXT,
+            FOREIGN KEY(job_run_id) REFERENCES job_runs(id)
+        )",
+        [],
+    )?;
+
+    // Artifact contents moved to `storage::artifacts`, `content` only holds
+    // the text of artifacts saved before until they are migrated
+    add_column_if_missing(&conn, "job_artifacts", "sha256", "TEXT")?;
+    add_column_if_missing(&conn, "job_artifacts", "size", "INTEGER")?;
+    add_column_if_missing(&conn, "job_artifacts", "media_type", "TEXT")?;
+
+    // Create workers table for the worker registry
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS workers (
+            id TEXT PRIMARY KEY,
+            url TEXT,
+            labels TEXT NOT NULL,
+            capacity INTEGER NOT NULL,
+            status TEXT NOT NULL,
+            registered_at DATETIME NOT NULL,
+            last_seen DATETIME NOT NULL
+        )",
+        [],
+    )?;
+
+    // Create targets table, which replaced targets.json
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS targets (
+            name TEXT PRIMARY KEY,
+            repository TEXT NOT NULL,
+            branch TEXT NOT NULL,
+            created_at DATETIME NOT NULL,
+            settings TEXT NOT NULL DEFAULT '{}'
+        )",
+        [],
+    )?;
+
+    // Create target_configs table, every pipeline configuration fetched for
+    // a target. The newest is the current one.
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS target_configs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            target TEXT NOT NULL,
+            commit_sha TEXT,
+            config TEXT NOT NULL,
+            fetched_at DATETIME NOT NULL
+        )",
+        [],
+    )?;
+
+    // Create target_secrets table, values are encrypted with the master key
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS target_secrets (
+            target TEXT NOT NULL,
+            name TEXT NOT NULL,
+            nonce BLOB NOT NULL,
+            value BLOB NOT NULL,
+            created_at DATETIME NOT NULL,
+            updated_at DATETIME NOT NULL,
+            PRIMARY KEY (target, name)
+        )",
+        [],
+    )?;
+
+    // Index of the chunk files job logs are stored in, see `storage::logs`
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS log_chunks (
+            job_run_id TEXT NOT NULL,
+            chunk_index INTEGER NOT NULL,
+            byte_offset INTEGER NOT NULL,
+            byte_length INTEGER NOT NULL,
+            first_line INTEGER NOT NULL,
+            line_count INTEGER NOT NULL,
+            compression TEXT NOT NULL,
+            PRIMARY KEY (job_run_id, chunk_index),
             FOREIGN KEY(job_run_id) REFERENCES job_runs(id)
         )",
         [],
@@ -72,11 +175,58 @@ pub fn init_database() -> SqlResult<()> {
         [],
     )?;
 
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_job_runs_worker_id 
+         ON job_runs(worker_id)",
+        [],
+    )?;
+
     conn.execute(
         "CREATE INDEX IF NOT EXISTS idx_job_artifacts_job_run 
          ON job_artifacts(job_run_id)",
         [],
     )?;
 
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_target_configs_target
+         ON target_configs(target, id)",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_targets_source
+         ON targets(repository, branch)",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_pipeline_runs_source
+         ON pipeline_runs(repository, branch, start_time)",
+        [],
+    )?;
+
+    Ok(())
+}
+
+// Brings databases created by older versions up to the current schema
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str
+) -> SqlResult<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt.query_map([], |row| row.get::<_, String>(1))?
+        .collect::<SqlResult<Vec<_>>>()?
+        .iter()
+        .any(|name| name == column);
+
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            [],
+        )?;
+    }
+
     Ok(())
 }
\ No newline at end of file
@@ -1,33 +1,70 @@
-use rusqlite::{Connection, params, Result as SqlResult};
+use rusqlite::{Connection, params, Result as SqlResult, TransactionBehavior};
 use chrono::{DateTime, Utc};
-use crate::models::pipeline::{PipelineRun, PipelineStatus};
-use crate::models::job::{JobRun, JobStatus, JobArtifact};
+use std::collections::HashSet;
+use crate::models::pipeline::{Parameters, PipelineRun, PipelineStatus};
+use crate::models::job::{ArtifactInfo, JobRun, JobStatus};
+use crate::models::worker::{Worker, WorkerStatus};
+use crate::models::secret::SecretInfo;
+use crate::models::target::{PipelineConfigVersion, Target};
+use crate::storage::artifacts::Blob;
+use crate::storage::logs;
+use crate::utils::secrets;
 use super::init::DATABASE_FILE;
 use uuid::Uuid;
 
-pub fn create_pipeline_run(
-    name: &str,
-    repository: &str,
-    branch: &str,
-    total_jobs: i32
-) -> SqlResult<String> {
+/// Source and lineage of a new pipeline run.
+pub struct NewPipelineRun<'a> {
+    pub name: &'a str,
+    pub repository: &'a str,
+    pub branch: &'a str,
+    pub commit_sha: Option<&'a str>,
+    pub pipeline_config: &'a str,
+    pub parent_run_id: Option<&'a str>,
+    pub attempt: i32,
+    pub total_jobs: i32,
+    /// Target the run was triggered through.
+    pub target: Option<&'a str>,
+    pub parameters: &'a Parameters,
+}
+
+/// What is needed to start a pipeline run again.
+pub struct RunSource {
+    pub repository: String,
+    pub branch: String,
+    pub commit_sha: Option<String>,
+    pub pipeline_config: Option<String>,
+    pub status: PipelineStatus,
+    pub attempt: i32,
+    pub target: Option<String>,
+    pub parameters: Parameters,
+}
+
+pub fn create_pipeline_run(run: &NewPipelineRun) -> SqlResult<String> {
     let conn = Connection::open(DATABASE_FILE)?;
     let id = Uuid::new_v4().to_string();
     
     conn.execute(
         "INSERT INTO pipeline_runs (
             id, pipeline_name, repository, branch, status, 
-            start_time, total_jobs, current_job_index
-        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            start_time, total_jobs, current_job_index,
+            commit_sha, pipeline_config, parent_run_id, attempt,
+            target, parameters
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
         params![
             id,
-            name,
-            repository,
-            branch,
+            run.name,
+            run.repository,
+            run.branch,
             PipelineStatus::Pending.to_string(),
             Utc::now(),
-            total_jobs,
-            0
+            run.total_jobs,
+            0,
+            run.commit_sha,
+            run.pipeline_config,
+            run.parent_run_id,
+            run.attempt,
+            run.target,
+            json_to_sql(run.parameters)
         ],
     )?;
     
@@ -37,7 +74,8 @@ pub fn create_pipeline_run(
 pub fn create_job_run(
     pipeline_run_id: &str,
     job_name: &str,
-    job_index: i32
+    job_index: i32,
+    attempt: i32
 ) -> SqlResult<String> {
     let conn = Connection::open(DATABASE_FILE)?;
     let id = Uuid::new_v4().to_string();
@@ -45,86 +83,325 @@ pub fn create_job_run(
     conn.execute(
         "INSERT INTO job_runs (
             id, pipeline_run_id, job_name, job_index, 
-            status, start_time
-        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            status, start_time, attempt
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
         params![
             id,
             pipeline_run_id,
             job_name,
             job_index,
             JobStatus::Pending.to_string(),
-            Utc::now()
+            Utc::now(),
+            attempt
         ],
     )?;
     
     Ok(id)
 }
 
+pub fn get_run_source(id: &str) -> SqlResult<Option<RunSource>> {
+    let conn = Connection::open(DATABASE_FILE)?;
+
+    match conn.query_row(
+        "SELECT repository, branch, commit_sha, pipeline_config, status, attempt,
+                target, parameters
+         FROM pipeline_runs WHERE id = ?1",
+        params![id],
+        |row| Ok(RunSource {
+            repository: row.get(0)?,
+            branch: row.get(1)?,
+            commit_sha: row.get(2)?,
+            pipeline_config: row.get(3)?,
+            status: parse_status(row.get::<_, String>(4)?)?,
+            attempt: row.get(5)?,
+            target: row.get(6)?,
+            parameters: json_from_sql(row, 7)?,
+        }),
+    ) {
+        Ok(source) => Ok(Some(source)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn get_job_run(id: &str) -> SqlResult<Option<JobRun>> {
+    let conn = Connection::open(DATABASE_FILE)?;
+
+    match conn.query_row(
+        "SELECT id, pipeline_run_id, job_name, job_index, status,
+                start_time, end_time, duration_seconds, attempt, queue_reason
+         FROM job_runs WHERE id = ?1",
+        params![id],
+        |row| Ok(JobRun {
+            id: row.get(0)?,
+            pipeline_run_id: row.get(1)?,
+            job_name: row.get(2)?,
+            job_index: row.get(3)?,
+            status: parse_status(row.get::<_, String>(4)?)?,
+            start_time: row.get(5)?,
+            end_time: row.get(6)?,
+            duration_seconds: row.get(7)?,
+            attempt: row.get(8)?,
+            queue_reason: row.get(9)?,
+        }),
+    ) {
+        Ok(job_run) => Ok(Some(job_run)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// The latest attempt of each job in a run, as (id, job_index, status, attempt).
+pub fn get_latest_job_runs(pipeline_run_id: &str) -> SqlResult<Vec<(String, i32, JobStatus, i32)>> {
+    let conn = Connection::open(DATABASE_FILE)?;
+    let mut stmt = conn.prepare(
+        "SELECT id, job_index, status, attempt
+         FROM job_runs jr
+         WHERE pipeline_run_id = ?1
+           AND attempt = (
+               SELECT MAX(attempt) FROM job_runs
+               WHERE pipeline_run_id = jr.pipeline_run_id AND job_index = jr.job_index
+           )
+         ORDER BY job_index"
+    )?;
+
+    let jobs = stmt.query_map(params![pipeline_run_id], |row| {
+        Ok((row.get(0)?, row.get(1)?, parse_status(row.get::<_, String>(2)?)?, row.get(3)?))
+    })?
+    .collect::<SqlResult<Vec<_>>>()?;
+
+    Ok(jobs)
+}
+
+/// How many tries, as `job_runs` rows, each job of a run has had so far, as
+/// (job_index, count).
+pub fn count_job_runs(pipeline_run_id: &str) -> SqlResult<Vec<(i32, u32)>> {
+    let conn = Connection::open(DATABASE_FILE)?;
+    let mut stmt = conn.prepare(
+        "SELECT job_index, COUNT(*) FROM job_runs
+         WHERE pipeline_run_id = ?1
+         GROUP BY job_index"
+    )?;
+
+    let counts = stmt.query_map(params![pipeline_run_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<SqlResult<Vec<_>>>()?;
+
+    Ok(counts)
+}
+
+/// Puts a finished run back into the running state for a retry of some of
+/// its jobs. Each retried job gets a new pending row with the next attempt.
+/// Returns false, changing nothing, if the run is not finished or one of
+/// the jobs already has that attempt, e.g. because another retry came first.
+pub fn begin_job_retry(
+    pipeline_run_id: &str,
+    jobs: &[(String, i32, i32)]
+) -> SqlResult<bool> {
+    let mut conn = Connection::open(DATABASE_FILE)?;
+    // Taking the write lock up front makes concurrent retries of a run wait
+    // for each other instead of both passing the checks
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+    let updated = tx.execute(
+        "UPDATE pipeline_runs
+         SET status = ?1, end_time = NULL, duration_seconds = NULL
+         WHERE id = ?2 AND status NOT IN (?1, ?3)",
+        params![
+            PipelineStatus::Running.to_string(),
+            pipeline_run_id,
+            PipelineStatus::Pending.to_string(),
+        ],
+    )?;
+    if updated == 0 {
+        return Ok(false);
+    }
+
+    for (job_name, job_index, attempt) in jobs {
+        let latest: i32 = tx.query_row(
+            "SELECT COALESCE(MAX(attempt), 0) FROM job_runs
+             WHERE pipeline_run_id = ?1 AND job_index = ?2",
+            params![pipeline_run_id, job_index],
+            |row| row.get(0),
+        )?;
+        if latest + 1 != *attempt {
+            return Ok(false);
+        }
+
+        tx.execute(
+            "INSERT INTO job_runs (
+                id, pipeline_run_id, job_name, job_index,
+                status, start_time, attempt
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                Uuid::new_v4().to_string(),
+                pipeline_run_id,
+                job_name,
+                job_index,
+                JobStatus::Pending.to_string(),
+                Utc::now(),
+                attempt
+            ],
+        )?;
+    }
+
+    tx.commit()?;
+    Ok(true)
+}
+
+pub fn parse_status<T: std::str::FromStr<Err = String>>(status: String) -> SqlResult<T> {
+    status.parse().map_err(|e: String| rusqlite::Error::FromSqlConversionFailure(
+        0,
+        rusqlite::types::Type::Text,
+        e.into(),
+    ))
+}
+
 pub fn update_job_status(id: &str, status: JobStatus, output: Option<&str>) -> SqlResult<()> {
     let conn = Connection::open(DATABASE_FILE)?;
     let now = Utc::now();
     
-    let start_time: DateTime<Utc> = conn.query_row(
-        "SELECT start_time FROM job_runs WHERE id = ?1",
+    let (pipeline_run_id, previous_status, start_time): (String, String, DateTime<Utc>) = conn.query_row(
+        "SELECT pipeline_run_id, status, start_time FROM job_runs WHERE id = ?1",
         params![id],
-        |row| row.get(0),
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
     )?;
+    let was_running = previous_status == JobStatus::Running.to_string();
 
-    let duration = (now - start_time).num_seconds();
+    // A job starts when it is dispatched to a worker, not when it is queued
+    if status == JobStatus::Running {
+        if !was_running {
+            conn.execute(
+                "UPDATE job_runs SET status = ?1, start_time = ?2, queue_reason = NULL WHERE id = ?3",
+                params![status.to_string(), now, id],
+            )?;
+            conn.execute(
+                "UPDATE pipeline_runs SET status = ?1 WHERE id = ?2 AND status = ?3",
+                params![
+                    PipelineStatus::Running.to_string(),
+                    pipeline_run_id,
+                    PipelineStatus::Pending.to_string()
+                ],
+            )?;
+        }
+        if let Some(output) = output {
+            replace_job_log(id, output)?;
+        }
+        return Ok(());
+    }
+
+    // The log is complete by the time followers see the job finish
+    if let Some(output) = output {
+        replace_job_log(id, output)?;
+    }
+    logs::seal(id).map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+    // Jobs that never reached a worker have no meaningful duration
+    let duration = was_running.then(|| (now - start_time).num_seconds());
 
     conn.execute(
         "UPDATE job_runs 
-         SET status = ?1, end_time = ?2, duration_seconds = ?3, output = ?4 
-         WHERE id = ?5",
+         SET status = ?1, end_time = ?2, duration_seconds = ?3
+         WHERE id = ?4",
         params![
             status.to_string(),
             now,
             duration,
-            output,
             id
         ],
     )?;
 
     // Update pipeline progress if job is complete
-    if status != JobStatus::Running && status != JobStatus::Pending {
-        let pipeline_run_id: String = conn.query_row(
-            "SELECT pipeline_run_id FROM job_runs WHERE id = ?1",
-            params![id],
-            |row| row.get(0),
-        )?;
-
+    if status != JobStatus::Pending {
         update_pipeline_progress(&pipeline_run_id)?;
     }
 
     Ok(())
 }
 
+/// Stores output reported with a job's status in place of the output
+/// reported before. Once the worker has streamed the job's log, the streamed
+/// log is kept instead.
+pub fn replace_job_log(id: &str, output: &str) -> SqlResult<()> {
+    let conn = Connection::open(DATABASE_FILE)?;
+    let streamed: bool = conn.query_row(
+        "SELECT log_streamed FROM job_runs WHERE id = ?1",
+        params![id],
+        |row| row.get(0),
+    )?;
+    if streamed {
+        return Ok(());
+    }
+
+    logs::replace(id, &secrets::mask(id, output))
+        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+}
+
+/// Records that a worker streamed output to the job's log, which output
+/// reported with the job's status then no longer replaces.
+pub fn mark_log_streamed(id: &str) -> SqlResult<()> {
+    let conn = Connection::open(DATABASE_FILE)?;
+    conn.execute(
+        "UPDATE job_runs SET log_streamed = 1 WHERE id = ?1 AND log_streamed = 0",
+        params![id],
+    )?;
+    Ok(())
+}
+
+/// Adds a line from the master, e.g. why a job failed, to the end of the
+/// job's log.
+pub fn append_job_note(id: &str, note: &str) -> SqlResult<()> {
+    let to_sql_error = |e| rusqlite::Error::ToSqlConversionFailure(Box::new(e));
+    let size = logs::size(id).map_err(to_sql_error)?;
+    let ends_line = size == 0 || logs::read(id, size - 1, size).map_err(to_sql_error)? == b"\n";
+    let separator = if ends_line { "" } else { "\n" };
+    logs::append(id, &format!("{}{}\n", separator, note.trim_end()))
+        .map_err(to_sql_error)?;
+    Ok(())
+}
+
 pub fn update_pipeline_progress(pipeline_run_id: &str) -> SqlResult<()> {
     let conn = Connection::open(DATABASE_FILE)?;
     
     // Get total and completed jobs
-    let (total_jobs, completed_jobs, failed_jobs): (i32, i32, i32) = conn.query_row(
+    // Only the latest attempt of each job counts towards progress
+    let (total_jobs, completed_jobs, failed_jobs, unfinished_jobs): (i32, i32, i32, i32) = conn.query_row(
         "SELECT 
             total_jobs,
-            COUNT(CASE WHEN status = 'succeeded' THEN 1 END),
-            COUNT(CASE WHEN status = 'failed' THEN 1 END)
+            COUNT(CASE WHEN jr.status = 'succeeded' THEN 1 END),
+            COUNT(CASE WHEN jr.status IN ('failed', 'error', 'timed_out') THEN 1 END),
+            COUNT(CASE WHEN jr.status IN ('pending', 'queued', 'running') THEN 1 END)
          FROM pipeline_runs pr
          LEFT JOIN job_runs jr ON pr.id = jr.pipeline_run_id
+            AND jr.attempt = (
+                SELECT MAX(attempt) FROM job_runs
+                WHERE pipeline_run_id = jr.pipeline_run_id AND job_index = jr.job_index
+            )
          WHERE pr.id = ?1
          GROUP BY pr.id",
         params![pipeline_run_id],
-        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
     )?;
 
-    // Update pipeline status based on job statuses
-    let new_status = if failed_jobs > 0 {
+    // Update pipeline status based on job statuses. The run only finishes
+    // once none of its jobs is still waiting or running.
+    let new_status = if unfinished_jobs > 0 {
+        PipelineStatus::Running
+    } else if failed_jobs > 0 || completed_jobs < total_jobs {
         PipelineStatus::Failed
-    } else if completed_jobs == total_jobs {
-        PipelineStatus::Completed
     } else {
-        PipelineStatus::Running
+        PipelineStatus::Completed
     };
 
+    // A cancelled run keeps its status and end time even if a job reports late
+    let current_status: String = conn.query_row(
+        "SELECT status FROM pipeline_runs WHERE id = ?1",
+        params![pipeline_run_id],
+        |row| row.get(0),
+    )?;
+    if current_status == PipelineStatus::Cancelled.to_string() {
+        return Ok(());
+    }
+
     // Update pipeline run
     if new_status == PipelineStatus::Completed || new_status == PipelineStatus::Failed {
         let now = Utc::now();
@@ -164,27 +441,382 @@ pub fn update_pipeline_progress(pipeline_run_id: &str) -> SqlResult<()> {
     Ok(())
 }
 
+pub fn get_pipeline_run_status(id: &str) -> SqlResult<Option<PipelineStatus>> {
+    let conn = Connection::open(DATABASE_FILE)?;
+
+    match conn.query_row(
+        "SELECT status FROM pipeline_runs WHERE id = ?1",
+        params![id],
+        |row| row.get::<_, String>(0),
+    ) {
+        Ok(status) => parse_status(status).map(Some),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Marks a pipeline run and all of its unfinished jobs as cancelled.
+/// Returns the ids of the jobs that were running at the time.
+pub fn cancel_pipeline_run(id: &str) -> SqlResult<Vec<String>> {
+    finalize_pipeline_run(id, PipelineStatus::Cancelled, JobStatus::Cancelled, JobStatus::Cancelled)
+}
+
+/// Pins or unpins a run. Returns false if there is no such run.
+pub fn set_pipeline_run_pinned(id: &str, pinned: bool) -> SqlResult<bool> {
+    let conn = Connection::open(DATABASE_FILE)?;
+    let updated = conn.execute(
+        "UPDATE pipeline_runs SET pinned = ?1 WHERE id = ?2",
+        params![pinned, id],
+    )?;
+    Ok(updated > 0)
+}
+
+/// Finished runs that are beyond the newest `keep_runs` runs of their
+/// target, or of their repository and branch for runs without one, or that
+/// started before `started_before`.
+/// Pinned runs are never returned.
+pub fn get_expired_pipeline_runs(
+    keep_runs: Option<usize>,
+    started_before: Option<DateTime<Utc>>,
+) -> SqlResult<Vec<String>> {
+    let conn = Connection::open(DATABASE_FILE)?;
+    let mut stmt = conn.prepare(
+        "SELECT id FROM (
+             SELECT id, status, start_time, pinned,
+                    ROW_NUMBER() OVER (
+                        PARTITION BY COALESCE(target, repository || ' ' || branch)
+                        ORDER BY start_time DESC
+                    ) AS position
+             FROM pipeline_runs
+         )
+         WHERE pinned = 0 AND status NOT IN (?1, ?2)
+           AND (position > ?3 OR start_time < ?4)
+         ORDER BY start_time"
+    )?;
+
+    let ids = stmt.query_map(
+        params![
+            PipelineStatus::Pending.to_string(),
+            PipelineStatus::Running.to_string(),
+            keep_runs.map(|keep| keep as i64),
+            started_before,
+        ],
+        |row| row.get(0),
+    )?
+    .collect::<SqlResult<Vec<_>>>()?;
+
+    Ok(ids)
+}
+
+/// Deletes a run along with its jobs and their artifact and log records, and
+/// returns the ids of the deleted jobs. Their log files and artifact
+/// contents are left to `storage`.
+pub fn delete_pipeline_run(id: &str) -> SqlResult<Vec<String>> {
+    let mut conn = Connection::open(DATABASE_FILE)?;
+    let tx = conn.transaction()?;
+    let job_ids = delete_run_records(&tx, id)?;
+    tx.commit()?;
+
+    Ok(job_ids)
+}
+
+fn delete_run_records(conn: &Connection, id: &str) -> SqlResult<Vec<String>> {
+    let job_ids = conn.prepare("SELECT id FROM job_runs WHERE pipeline_run_id = ?1")?
+        .query_map(params![id], |row| row.get(0))?
+        .collect::<SqlResult<Vec<String>>>()?;
+
+    for table in ["job_artifacts", "log_chunks"] {
+        conn.execute(
+            &format!(
+                "DELETE FROM {} WHERE job_run_id IN (SELECT id FROM job_runs WHERE pipeline_run_id = ?1)",
+                table
+            ),
+            params![id],
+        )?;
+    }
+    conn.execute("DELETE FROM job_runs WHERE pipeline_run_id = ?1", params![id])?;
+    conn.execute("DELETE FROM pipeline_runs WHERE id = ?1", params![id])?;
+
+    Ok(job_ids)
+}
+
+/// Deletes the records of artifacts saved before `cutoff`, except those of
+/// pinned runs and of runs still in progress, whose later jobs may need
+/// them, and returns how many there were.
+pub fn delete_artifacts_before(cutoff: DateTime<Utc>) -> SqlResult<usize> {
+    let conn = Connection::open(DATABASE_FILE)?;
+    conn.execute(
+        "DELETE FROM job_artifacts
+         WHERE created_at < ?1
+           AND job_run_id NOT IN (
+               SELECT jr.id FROM job_runs jr
+               JOIN pipeline_runs pr ON pr.id = jr.pipeline_run_id
+               WHERE pr.pinned = 1 OR pr.status IN (?2, ?3)
+           )",
+        params![cutoff, PipelineStatus::Pending.to_string(), PipelineStatus::Running.to_string()],
+    )
+}
+
+/// Checksums of the content every artifact record points at.
+pub fn get_artifact_hashes() -> SqlResult<HashSet<String>> {
+    let conn = Connection::open(DATABASE_FILE)?;
+    let mut stmt = conn.prepare(
+        "SELECT DISTINCT sha256 FROM job_artifacts WHERE sha256 IS NOT NULL"
+    )?;
+
+    let hashes = stmt.query_map([], |row| row.get(0))?
+        .collect::<SqlResult<HashSet<_>>>()?;
+
+    Ok(hashes)
+}
+
+/// Runs that were still pending or running, oldest first.
+pub fn get_unfinished_pipeline_runs() -> SqlResult<Vec<String>> {
+    let conn = Connection::open(DATABASE_FILE)?;
+    let mut stmt = conn.prepare(
+        "SELECT id FROM pipeline_runs WHERE status IN (?1, ?2) ORDER BY start_time"
+    )?;
+
+    let ids = stmt.query_map(
+        params![PipelineStatus::Pending.to_string(), PipelineStatus::Running.to_string()],
+        |row| row.get(0),
+    )?
+    .collect::<SqlResult<Vec<_>>>()?;
+
+    Ok(ids)
+}
+
+/// Marks a run that can't be resumed after a restart as interrupted.
+/// Running jobs become errors and jobs that never started are skipped.
+pub fn interrupt_pipeline_run(id: &str) -> SqlResult<Vec<String>> {
+    finalize_pipeline_run(id, PipelineStatus::Interrupted, JobStatus::Error, JobStatus::Skipped)
+}
+
+/// Marks a pipeline run that ran past its timeout as failed. Running jobs
+/// become timed out and jobs that never started are skipped. Returns the
+/// ids of the jobs that were running at the time.
+pub fn time_out_pipeline_run(id: &str) -> SqlResult<Vec<String>> {
+    finalize_pipeline_run(id, PipelineStatus::Failed, JobStatus::TimedOut, JobStatus::Skipped)
+}
+
+// Ends a pipeline run early, settling each unfinished job with the status
+// given for running jobs or for jobs that never reached a worker
+fn finalize_pipeline_run(
+    id: &str,
+    run_status: PipelineStatus,
+    running_status: JobStatus,
+    pending_status: JobStatus
+) -> SqlResult<Vec<String>> {
+    let mut conn = Connection::open(DATABASE_FILE)?;
+    let tx = conn.transaction()?;
+    let now = Utc::now();
+
+    let unfinished = {
+        let mut stmt = tx.prepare(
+            "SELECT id, status, start_time FROM job_runs
+             WHERE pipeline_run_id = ?1 AND status IN (?2, ?3, ?4)"
+        )?;
+        let rows = stmt.query_map(
+            params![
+                id,
+                JobStatus::Pending.to_string(),
+                JobStatus::Queued.to_string(),
+                JobStatus::Running.to_string()
+            ],
+            |row| Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, DateTime<Utc>>(2)?,
+            )),
+        )?;
+        rows.collect::<SqlResult<Vec<_>>>()?
+    };
+
+    let mut running_jobs = Vec::new();
+    for (job_id, status, start_time) in unfinished {
+        // Jobs that never reached a worker have no meaningful duration
+        let (new_status, duration) = if status == JobStatus::Running.to_string() {
+            running_jobs.push(job_id.clone());
+            (&running_status, Some((now - start_time).num_seconds()))
+        } else {
+            (&pending_status, None)
+        };
+
+        tx.execute(
+            "UPDATE job_runs
+             SET status = ?1, end_time = ?2, duration_seconds = ?3
+             WHERE id = ?4",
+            params![new_status.to_string(), now, duration, job_id],
+        )?;
+    }
+
+    let start_time: DateTime<Utc> = tx.query_row(
+        "SELECT start_time FROM pipeline_runs WHERE id = ?1",
+        params![id],
+        |row| row.get(0),
+    )?;
+
+    tx.execute(
+        "UPDATE pipeline_runs
+         SET status = ?1, end_time = ?2, duration_seconds = ?3
+         WHERE id = ?4",
+        params![
+            run_status.to_string(),
+            now,
+            (now - start_time).num_seconds(),
+            id
+        ],
+    )?;
+
+    tx.commit()?;
+    Ok(running_jobs)
+}
+
+/// Records an artifact whose content is in the artifact store. An artifact
+/// the job saved under the same name before is replaced.
 pub fn save_job_artifact(
     job_run_id: &str,
     name: &str,
-    content: &str
-) -> SqlResult<String> {
-    let conn = Connection::open(DATABASE_FILE)?;
-    let id = Uuid::new_v4().to_string();
-    
-    conn.execute(
-        "INSERT INTO job_artifacts (id, job_run_id, name, content, created_at)
-         VALUES (?1, ?2, ?3, ?4, ?5)",
+    blob: &Blob,
+    media_type: &str,
+) -> SqlResult<ArtifactInfo> {
+    let mut conn = Connection::open(DATABASE_FILE)?;
+    let tx = conn.transaction()?;
+    let now = Utc::now();
+
+    tx.execute(
+        "DELETE FROM job_artifacts WHERE job_run_id = ?1 AND name = ?2",
+        params![job_run_id, name],
+    )?;
+    tx.execute(
+        "INSERT INTO job_artifacts (id, job_run_id, name, content, created_at, sha256, size, media_type)
+         VALUES (?1, ?2, ?3, '', ?4, ?5, ?6, ?7)",
         params![
-            id,
+            Uuid::new_v4().to_string(),
             job_run_id,
             name,
-            content,
-            Utc::now()
+            now,
+            blob.sha256,
+            blob.size,
+            media_type
         ],
     )?;
-    
-    Ok(id)
+    tx.commit()?;
+
+    Ok(ArtifactInfo {
+        name: name.to_string(),
+        size: blob.size,
+        media_type: media_type.to_string(),
+        sha256: blob.sha256.clone(),
+        created_at: now,
+    })
+}
+
+pub fn get_job_artifacts(job_run_id: &str) -> SqlResult<Vec<ArtifactInfo>> {
+    let conn = Connection::open(DATABASE_FILE)?;
+    let mut stmt = conn.prepare(
+        "SELECT name, size, media_type, sha256, created_at
+         FROM job_artifacts
+         WHERE job_run_id = ?1 AND sha256 IS NOT NULL
+         ORDER BY created_at"
+    )?;
+
+    let artifacts = stmt.query_map(params![job_run_id], artifact_from_row)?
+        .collect::<SqlResult<Vec<_>>>()?;
+
+    Ok(artifacts)
+}
+
+pub fn get_job_artifact(job_run_id: &str, name: &str) -> SqlResult<Option<ArtifactInfo>> {
+    let conn = Connection::open(DATABASE_FILE)?;
+
+    match conn.query_row(
+        "SELECT name, size, media_type, sha256, created_at
+         FROM job_artifacts
+         WHERE job_run_id = ?1 AND name = ?2 AND sha256 IS NOT NULL",
+        params![job_run_id, name],
+        artifact_from_row,
+    ) {
+        Ok(artifact) => Ok(Some(artifact)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Artifacts of a run, from the latest attempt of each job, along with the
+/// name of the job they belong to.
+pub fn get_run_artifacts(pipeline_run_id: &str) -> SqlResult<Vec<(String, ArtifactInfo)>> {
+    let conn = Connection::open(DATABASE_FILE)?;
+    let mut stmt = conn.prepare(
+        "SELECT a.name, a.size, a.media_type, a.sha256, a.created_at, jr.job_name
+         FROM job_artifacts a
+         JOIN job_runs jr ON jr.id = a.job_run_id
+         WHERE jr.pipeline_run_id = ?1 AND a.sha256 IS NOT NULL
+           AND jr.attempt = (
+               SELECT MAX(attempt) FROM job_runs
+               WHERE pipeline_run_id = jr.pipeline_run_id AND job_index = jr.job_index
+           )
+         ORDER BY jr.job_index, a.created_at"
+    )?;
+
+    let artifacts = stmt.query_map(params![pipeline_run_id], |row| {
+        Ok((row.get(5)?, artifact_from_row(row)?))
+    })?
+    .collect::<SqlResult<Vec<_>>>()?;
+
+    Ok(artifacts)
+}
+
+/// The latest successful attempt of a job in a run, with its artifacts.
+pub fn get_succeeded_job_artifacts(
+    pipeline_run_id: &str,
+    job_name: &str,
+) -> SqlResult<Option<(String, Vec<ArtifactInfo>)>> {
+    let conn = Connection::open(DATABASE_FILE)?;
+
+    let job_run_id: String = match conn.query_row(
+        "SELECT id FROM job_runs
+         WHERE pipeline_run_id = ?1 AND job_name = ?2 AND status = ?3
+         ORDER BY attempt DESC
+         LIMIT 1",
+        params![pipeline_run_id, job_name, JobStatus::Succeeded.to_string()],
+        |row| row.get(0),
+    ) {
+        Ok(id) => id,
+        Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let artifacts = get_job_artifacts(&job_run_id)?;
+    Ok(Some((job_run_id, artifacts)))
+}
+
+/// The most recent run of a repository and branch that completed successfully.
+pub fn get_latest_successful_run(repository: &str, branch: &str) -> SqlResult<Option<String>> {
+    let conn = Connection::open(DATABASE_FILE)?;
+
+    match conn.query_row(
+        "SELECT id FROM pipeline_runs
+         WHERE repository = ?1 AND branch = ?2 AND status = ?3
+         ORDER BY start_time DESC
+         LIMIT 1",
+        params![repository, branch, PipelineStatus::Completed.to_string()],
+        |row| row.get(0),
+    ) {
+        Ok(id) => Ok(Some(id)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn artifact_from_row(row: &rusqlite::Row) -> SqlResult<ArtifactInfo> {
+    Ok(ArtifactInfo {
+        name: row.get(0)?,
+        size: row.get(1)?,
+        media_type: row.get(2)?,
+        sha256: row.get(3)?,
+        created_at: row.get(4)?,
+    })
 }
 
 pub fn get_pipeline_status(pipeline_name: &str) -> SqlResult<Option<(PipelineRun, Vec<JobRun>)>> {
@@ -194,7 +826,9 @@ pub fn get_pipeline_status(pipeline_name: &str) -> SqlResult<Option<(PipelineRun
     let pipeline_run = match conn.query_row(
         "SELECT id, pipeline_name, repository, branch, status, 
                 start_time, end_time, duration_seconds, 
-                current_job_index, total_jobs
+                current_job_index, total_jobs,
+                commit_sha, parent_run_id, attempt, pinned,
+                target, parameters
          FROM pipeline_runs 
          WHERE pipeline_name = ?1 
          ORDER BY start_time DESC 
@@ -206,10 +840,16 @@ pub fn get_pipeline_status(pipeline_name: &str) -> SqlResult<Option<(PipelineRun
                 pipeline_name: row.get(1)?,
                 repository: row.get(2)?,
                 branch: row.get(3)?,
-                status: serde_json::from_str(&row.get::<_, String>(4)?).unwrap(),
+                commit_sha: row.get(10)?,
+                status: parse_status(row.get::<_, String>(4)?)?,
                 start_time: row.get(5)?,
                 end_time: row.get(6)?,
                 duration_seconds: row.get(7)?,
+                parent_run_id: row.get(11)?,
+                attempt: row.get(12)?,
+                pinned: row.get(13)?,
+                target: row.get(14)?,
+                parameters: json_from_sql(row, 15)?,
             })
         },
     ) {
@@ -221,10 +861,10 @@ pub fn get_pipeline_status(pipeline_name: &str) -> SqlResult<Option<(PipelineRun
     // Get all jobs for this pipeline run
     let mut stmt = conn.prepare(
         "SELECT id, job_name, job_index, status, start_time, 
-                end_time, duration_seconds, output
+                end_time, duration_seconds, attempt, queue_reason
          FROM job_runs 
          WHERE pipeline_run_id = ?1 
-         ORDER BY job_index"
+         ORDER BY job_index, attempt"
     )?;
 
     let jobs = stmt.query_map(params![pipeline_run.id], |row| {
@@ -233,13 +873,608 @@ pub fn get_pipeline_status(pipeline_name: &str) -> SqlResult<Option<(PipelineRun
             pipeline_run_id: pipeline_run.id.clone(),
             job_name: row.get(1)?,
             job_index: row.get(2)?,
-            status: serde_json::from_str(&row.get::<_, String>(3)?).unwrap(),
+            status: parse_status(row.get::<_, String>(3)?)?,
             start_time: row.get(4)?,
             end_time: row.get(5)?,
             duration_seconds: row.get(6)?,
+            attempt: row.get(7)?,
+            queue_reason: row.get(8)?,
         })
     })?
     .collect::<SqlResult<Vec<_>>>()?;
 
     Ok(Some((pipeline_run, jobs)))
-}
\ No newline at end of file
+}
+
+/// Registers a worker, or updates it if `id` is already known. Returns the
+/// worker's id.
+pub fn register_worker(
+    id: Option<&str>,
+    url: Option<&str>,
+    labels: &[String],
+    capacity: i32
+) -> SqlResult<String> {
+    let conn = Connection::open(DATABASE_FILE)?;
+    let id = id.map(str::to_string).unwrap_or_else(|| Uuid::new_v4().to_string());
+    let now = Utc::now();
+
+    conn.execute(
+        "INSERT INTO workers (id, url, labels, capacity, status, registered_at, last_seen)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)
+         ON CONFLICT(id) DO UPDATE SET
+            url = excluded.url,
+            labels = excluded.labels,
+            capacity = excluded.capacity,
+            status = excluded.status,
+            last_seen = excluded.last_seen",
+        params![
+            id,
+            url,
+            serde_json::to_string(labels).unwrap(),
+            capacity,
+            WorkerStatus::Online.to_string(),
+            now
+        ],
+    )?;
+
+    Ok(id)
+}
+
+/// Records a heartbeat and brings the worker back online. Returns false if
+/// the worker is not registered.
+pub fn record_worker_heartbeat(id: &str) -> SqlResult<bool> {
+    let conn = Connection::open(DATABASE_FILE)?;
+
+    let updated = conn.execute(
+        "UPDATE workers SET last_seen = ?1, status = ?2 WHERE id = ?3",
+        params![Utc::now(), WorkerStatus::Online.to_string(), id],
+    )?;
+
+    Ok(updated > 0)
+}
+
+const WORKER_COLUMNS: &str =
+    "w.id, w.url, w.labels, w.capacity, w.status, w.registered_at, w.last_seen,
+     (SELECT COUNT(*) FROM job_runs jr WHERE jr.worker_id = w.id AND jr.status = 'running')";
+
+fn worker_from_row(row: &rusqlite::Row) -> SqlResult<Worker> {
+    let labels: String = row.get(2)?;
+    Ok(Worker {
+        id: row.get(0)?,
+        url: row.get(1)?,
+        labels: serde_json::from_str(&labels).unwrap_or_default(),
+        capacity: row.get(3)?,
+        status: parse_status(row.get::<_, String>(4)?)?,
+        registered_at: row.get(5)?,
+        last_seen: row.get(6)?,
+        running_jobs: row.get(7)?,
+    })
+}
+
+pub fn get_workers() -> SqlResult<Vec<Worker>> {
+    let conn = Connection::open(DATABASE_FILE)?;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM workers w ORDER BY w.registered_at",
+        WORKER_COLUMNS
+    ))?;
+
+    let workers = stmt.query_map([], worker_from_row)?
+        .collect::<SqlResult<Vec<_>>>()?;
+
+    Ok(workers)
+}
+
+pub fn get_worker(id: &str) -> SqlResult<Option<Worker>> {
+    let conn = Connection::open(DATABASE_FILE)?;
+
+    match conn.query_row(
+        &format!("SELECT {} FROM workers w WHERE w.id = ?1", WORKER_COLUMNS),
+        params![id],
+        worker_from_row,
+    ) {
+        Ok(worker) => Ok(Some(worker)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Marks online workers that have not been seen since `cutoff` as offline
+/// and returns their ids.
+pub fn mark_stale_workers_offline(cutoff: DateTime<Utc>) -> SqlResult<Vec<String>> {
+    let conn = Connection::open(DATABASE_FILE)?;
+    let mut stmt = conn.prepare(
+        "UPDATE workers SET status = ?1
+         WHERE status = ?2 AND last_seen < ?3
+         RETURNING id"
+    )?;
+
+    let ids = stmt.query_map(
+        params![WorkerStatus::Offline.to_string(), WorkerStatus::Online.to_string(), cutoff],
+        |row| row.get(0),
+    )?
+    .collect::<SqlResult<Vec<_>>>()?;
+
+    Ok(ids)
+}
+
+pub fn get_running_jobs_on_worker(worker_id: &str) -> SqlResult<Vec<String>> {
+    let conn = Connection::open(DATABASE_FILE)?;
+    let mut stmt = conn.prepare(
+        "SELECT id FROM job_runs WHERE worker_id = ?1 AND status = ?2"
+    )?;
+
+    let ids = stmt.query_map(
+        params![worker_id, JobStatus::Running.to_string()],
+        |row| row.get(0),
+    )?
+    .collect::<SqlResult<Vec<_>>>()?;
+
+    Ok(ids)
+}
+
+/// Marks a job as waiting for a worker, with the reason it can't be
+/// dispatched yet.
+pub fn queue_job(id: &str, reason: &str) -> SqlResult<()> {
+    let conn = Connection::open(DATABASE_FILE)?;
+
+    let pipeline_run_id: String = conn.query_row(
+        "UPDATE job_runs SET status = ?1, queue_reason = ?2 WHERE id = ?3
+         RETURNING pipeline_run_id",
+        params![JobStatus::Queued.to_string(), reason, id],
+        |row| row.get(0),
+    )?;
+
+    // The run is under way once any of its jobs is ready to go
+    conn.execute(
+        "UPDATE pipeline_runs SET status = ?1 WHERE id = ?2 AND status = ?3",
+        params![
+            PipelineStatus::Running.to_string(),
+            pipeline_run_id,
+            PipelineStatus::Pending.to_string()
+        ],
+    )?;
+
+    Ok(())
+}
+
+/// Records which worker a job was dispatched to, and until when a pull
+/// worker holds its lease.
+pub fn assign_job_worker(
+    job_run_id: &str,
+    worker_id: Option<&str>,
+    lease_expires_at: Option<DateTime<Utc>>
+) -> SqlResult<()> {
+    let conn = Connection::open(DATABASE_FILE)?;
+
+    conn.execute(
+        "UPDATE job_runs SET worker_id = ?1, lease_expires_at = ?2 WHERE id = ?3",
+        params![worker_id, lease_expires_at, job_run_id],
+    )?;
+
+    Ok(())
+}
+
+/// The id and URL of the worker a job was dispatched to. Both are `None`
+/// for jobs sent to `WORKER_URL`, and the URL is `None` for pull workers.
+pub fn get_job_worker(job_run_id: &str) -> SqlResult<(Option<String>, Option<String>)> {
+    let conn = Connection::open(DATABASE_FILE)?;
+
+    conn.query_row(
+        "SELECT jr.worker_id, w.url FROM job_runs jr LEFT JOIN workers w ON w.id = jr.worker_id
+         WHERE jr.id = ?1",
+        params![job_run_id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+}
+
+/// Extends a lease held by `worker_id` on a running job. Returns false if
+/// the worker no longer holds it.
+pub fn renew_job_lease(
+    job_run_id: &str,
+    worker_id: &str,
+    lease_expires_at: DateTime<Utc>
+) -> SqlResult<bool> {
+    let conn = Connection::open(DATABASE_FILE)?;
+
+    let updated = conn.execute(
+        "UPDATE job_runs SET lease_expires_at = ?1
+         WHERE id = ?2 AND worker_id = ?3 AND status = ?4 AND lease_expires_at IS NOT NULL",
+        params![lease_expires_at, job_run_id, worker_id, JobStatus::Running.to_string()],
+    )?;
+
+    Ok(updated > 0)
+}
+
+/// Puts running jobs whose lease ran out before `now` back in the queue
+/// and returns their ids.
+pub fn expire_job_leases(now: DateTime<Utc>) -> SqlResult<Vec<String>> {
+    let conn = Connection::open(DATABASE_FILE)?;
+    let mut stmt = conn.prepare(
+        "UPDATE job_runs
+         SET status = ?1, queue_reason = 'Lease expired on worker ' || worker_id,
+             worker_id = NULL, lease_expires_at = NULL
+         WHERE status = ?2 AND lease_expires_at < ?3
+         RETURNING id"
+    )?;
+
+    let ids = stmt.query_map(
+        params![JobStatus::Queued.to_string(), JobStatus::Running.to_string(), now],
+        |row| row.get(0),
+    )?
+    .collect::<SqlResult<Vec<_>>>()?;
+
+    Ok(ids)
+}
+
+/// Adds a target. Returns false if a target of that name exists already.
+pub fn create_target(target: &Target) -> SqlResult<bool> {
+    let conn = Connection::open(DATABASE_FILE)?;
+    let inserted = conn.execute(
+        "INSERT INTO targets (name, repository, branch, created_at, settings)
+         VALUES (?1, ?2, ?3, ?4, ?5)
+         ON CONFLICT (name) DO NOTHING",
+        params![
+            target.name,
+            target.repository,
+            target.branch,
+            target.created_at,
+            json_to_sql(&target.settings),
+        ],
+    )?;
+    Ok(inserted > 0)
+}
+
+/// Adds the targets that don't exist yet, all at once, and returns how
+/// many were added.
+pub fn import_targets(targets: &[Target]) -> SqlResult<usize> {
+    let mut conn = Connection::open(DATABASE_FILE)?;
+    let tx = conn.transaction()?;
+
+    let mut imported = 0;
+    for target in targets {
+        imported += tx.execute(
+            "INSERT INTO targets (name, repository, branch, created_at, settings)
+             VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT (name) DO NOTHING",
+            params![
+                target.name,
+                target.repository,
+                target.branch,
+                target.created_at,
+                json_to_sql(&target.settings),
+            ],
+        )?;
+    }
+    tx.commit()?;
+
+    Ok(imported)
+}
+
+pub fn get_targets() -> SqlResult<Vec<Target>> {
+    let conn = Connection::open(DATABASE_FILE)?;
+    let mut stmt = conn.prepare(
+        "SELECT name, repository, branch, created_at, settings
+         FROM targets
+         ORDER BY created_at, name"
+    )?;
+
+    let targets = stmt.query_map([], target_from_row)?
+        .collect::<SqlResult<Vec<_>>>()?;
+
+    Ok(targets)
+}
+
+pub fn get_target(name: &str) -> SqlResult<Option<Target>> {
+    let conn = Connection::open(DATABASE_FILE)?;
+
+    match conn.query_row(
+        "SELECT name, repository, branch, created_at, settings
+         FROM targets WHERE name = ?1",
+        params![name],
+        target_from_row,
+    ) {
+        Ok(target) => Ok(Some(target)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// The target for a repository and branch. If several targets build the
+/// same branch, the one added first.
+pub fn get_target_for_branch(repository: &str, branch: &str) -> SqlResult<Option<Target>> {
+    let conn = Connection::open(DATABASE_FILE)?;
+
+    match conn.query_row(
+        "SELECT name, repository, branch, created_at, settings
+         FROM targets
+         WHERE repository = ?1 AND branch = ?2
+         ORDER BY created_at, name
+         LIMIT 1",
+        params![repository, branch],
+        target_from_row,
+    ) {
+        Ok(target) => Ok(Some(target)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Changes a target's repository, branch and settings. Returns false if
+/// there is no such target.
+pub fn update_target(target: &Target) -> SqlResult<bool> {
+    let conn = Connection::open(DATABASE_FILE)?;
+    let updated = conn.execute(
+        "UPDATE targets SET repository = ?1, branch = ?2, settings = ?3 WHERE name = ?4",
+        params![
+            target.repository,
+            target.branch,
+            json_to_sql(&target.settings),
+            target.name,
+        ],
+    )?;
+    Ok(updated > 0)
+}
+
+/// Outcome of deleting a target.
+pub enum TargetDeletion {
+    NotFound,
+    /// Nothing was deleted since some of the runs to purge are unfinished.
+    RunsInProgress,
+    /// The target is gone, along with `runs` runs whose jobs are listed so
+    /// that their log files can be deleted too.
+    Deleted { runs: usize, job_ids: Vec<String> },
+}
+
+/// Deletes a target along with its secrets and pipeline configurations.
+/// With `purge` its runs go too: those triggered through it, and those of
+/// its repository and branch that no target was recorded for.
+pub fn delete_target(name: &str, purge: bool) -> SqlResult<TargetDeletion> {
+    let mut conn = Connection::open(DATABASE_FILE)?;
+    let tx = conn.transaction()?;
+
+    let (repository, branch): (String, String) = match tx.query_row(
+        "SELECT repository, branch FROM targets WHERE name = ?1",
+        params![name],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ) {
+        Ok(source) => source,
+        Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(TargetDeletion::NotFound),
+        Err(e) => return Err(e),
+    };
+
+    let runs = if purge {
+        tx.prepare(
+            "SELECT id, status FROM pipeline_runs
+             WHERE target = ?1 OR (target IS NULL AND repository = ?2 AND branch = ?3)"
+        )?
+        .query_map(params![name, repository, branch], |row| {
+            Ok((row.get(0)?, parse_status(row.get::<_, String>(1)?)?))
+        })?
+        .collect::<SqlResult<Vec<(String, PipelineStatus)>>>()?
+    } else {
+        Vec::new()
+    };
+    if runs.iter().any(|(_, status)| matches!(status, PipelineStatus::Pending | PipelineStatus::Running)) {
+        return Ok(TargetDeletion::RunsInProgress);
+    }
+
+    let mut job_ids = Vec::new();
+    for (run_id, _) in &runs {
+        job_ids.extend(delete_run_records(&tx, run_id)?);
+    }
+    tx.execute("DELETE FROM target_secrets WHERE target = ?1", params![name])?;
+    tx.execute("DELETE FROM target_configs WHERE target = ?1", params![name])?;
+    tx.execute("DELETE FROM targets WHERE name = ?1", params![name])?;
+    tx.commit()?;
+
+    Ok(TargetDeletion::Deleted { runs: runs.len(), job_ids })
+}
+
+/// Stores a pipeline configuration fetched for a target as its current one.
+/// A configuration from the commit the current one came from is not stored
+/// again. Returns the current version and whether it is new.
+pub fn save_pipeline_config(
+    target: &str,
+    commit_sha: Option<&str>,
+    config: &str,
+) -> SqlResult<(PipelineConfigVersion, bool)> {
+    let mut conn = Connection::open(DATABASE_FILE)?;
+    let tx = conn.transaction()?;
+
+    let current = tx.query_row(
+        "SELECT id, commit_sha, fetched_at FROM target_configs
+         WHERE target = ?1 ORDER BY id DESC LIMIT 1",
+        params![target],
+        config_version_from_row,
+    );
+    match current {
+        Ok(current) if commit_sha.is_some() && current.commit_sha.as_deref() == commit_sha => {
+            return Ok((current, false));
+        }
+        Ok(_) | Err(rusqlite::Error::QueryReturnedNoRows) => {}
+        Err(e) => return Err(e),
+    }
+
+    let fetched_at = Utc::now();
+    tx.execute(
+        "INSERT INTO target_configs (target, commit_sha, config, fetched_at)
+         VALUES (?1, ?2, ?3, ?4)",
+        params![target, commit_sha, config, fetched_at],
+    )?;
+    let id = tx.last_insert_rowid();
+    tx.commit()?;
+
+    let version = PipelineConfigVersion {
+        id,
+        commit_sha: commit_sha.map(str::to_string),
+        fetched_at,
+    };
+    Ok((version, true))
+}
+
+/// A target's current pipeline configuration, or with `commit` the one
+/// fetched from that commit, which may be given as a prefix of its SHA.
+pub fn get_pipeline_config(
+    target: &str,
+    commit: Option<&str>,
+) -> SqlResult<Option<(PipelineConfigVersion, String)>> {
+    let conn = Connection::open(DATABASE_FILE)?;
+
+    match conn.query_row(
+        "SELECT id, commit_sha, fetched_at, config FROM target_configs
+         WHERE target = ?1 AND (?2 IS NULL OR substr(commit_sha, 1, length(?2)) = ?2)
+         ORDER BY id DESC LIMIT 1",
+        params![target, commit],
+        |row| Ok((config_version_from_row(row)?, row.get(3)?)),
+    ) {
+        Ok(config) => Ok(Some(config)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// The commits, at most `limit` of them, of a target's pipeline
+/// configurations whose SHA starts with `prefix`, newest first.
+pub fn find_pipeline_config_commits(target: &str, prefix: &str, limit: usize) -> SqlResult<Vec<String>> {
+    let conn = Connection::open(DATABASE_FILE)?;
+    let mut stmt = conn.prepare(
+        "SELECT commit_sha FROM target_configs
+         WHERE target = ?1 AND substr(commit_sha, 1, length(?2)) = ?2
+         GROUP BY commit_sha ORDER BY max(id) DESC LIMIT ?3"
+    )?;
+
+    let commits = stmt.query_map(params![target, prefix, limit as i64], |row| row.get(0))?
+        .collect::<SqlResult<Vec<_>>>()?;
+
+    Ok(commits)
+}
+
+/// Versions of a target's pipeline configuration, newest first.
+pub fn get_pipeline_config_versions(target: &str) -> SqlResult<Vec<PipelineConfigVersion>> {
+    let conn = Connection::open(DATABASE_FILE)?;
+    let mut stmt = conn.prepare(
+        "SELECT id, commit_sha, fetched_at FROM target_configs
+         WHERE target = ?1 ORDER BY id DESC"
+    )?;
+
+    let versions = stmt.query_map(params![target], config_version_from_row)?
+        .collect::<SqlResult<Vec<_>>>()?;
+
+    Ok(versions)
+}
+
+fn config_version_from_row(row: &rusqlite::Row) -> SqlResult<PipelineConfigVersion> {
+    Ok(PipelineConfigVersion {
+        id: row.get(0)?,
+        commit_sha: row.get(1)?,
+        fetched_at: row.get(2)?,
+    })
+}
+
+fn target_from_row(row: &rusqlite::Row) -> SqlResult<Target> {
+    Ok(Target {
+        name: row.get(0)?,
+        repository: row.get(1)?,
+        branch: row.get(2)?,
+        created_at: row.get(3)?,
+        settings: json_from_sql(row, 4)?,
+    })
+}
+
+// Settings and parameters are stored as JSON objects
+fn json_to_sql(map: &serde_json::Map<String, serde_json::Value>) -> String {
+    serde_json::Value::Object(map.clone()).to_string()
+}
+
+fn json_from_sql(row: &rusqlite::Row, index: usize) -> SqlResult<serde_json::Map<String, serde_json::Value>> {
+    let text: String = row.get(index)?;
+    serde_json::from_str(&text).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, Box::new(e))
+    })
+}
+
+/// Stores a target's secret, already encrypted, replacing any earlier value.
+pub fn save_target_secret(
+    target: &str,
+    name: &str,
+    nonce: &[u8],
+    value: &[u8],
+) -> SqlResult<SecretInfo> {
+    let conn = Connection::open(DATABASE_FILE)?;
+    let now = Utc::now();
+
+    conn.query_row(
+        "INSERT INTO target_secrets (target, name, nonce, value, created_at, updated_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?5)
+         ON CONFLICT(target, name) DO UPDATE SET
+            nonce = excluded.nonce,
+            value = excluded.value,
+            updated_at = excluded.updated_at
+         RETURNING target, name, created_at, updated_at",
+        params![target, name, nonce, value, now],
+        secret_info_from_row,
+    )
+}
+
+/// A target's secret as stored: its nonce and encrypted value.
+pub fn get_target_secret(target: &str, name: &str) -> SqlResult<Option<(Vec<u8>, Vec<u8>)>> {
+    let conn = Connection::open(DATABASE_FILE)?;
+
+    match conn.query_row(
+        "SELECT nonce, value FROM target_secrets WHERE target = ?1 AND name = ?2",
+        params![target, name],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ) {
+        Ok(secret) => Ok(Some(secret)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn get_target_secret_info(target: &str, name: &str) -> SqlResult<Option<SecretInfo>> {
+    let conn = Connection::open(DATABASE_FILE)?;
+
+    match conn.query_row(
+        "SELECT target, name, created_at, updated_at
+         FROM target_secrets WHERE target = ?1 AND name = ?2",
+        params![target, name],
+        secret_info_from_row,
+    ) {
+        Ok(info) => Ok(Some(info)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn get_target_secrets(target: &str) -> SqlResult<Vec<SecretInfo>> {
+    let conn = Connection::open(DATABASE_FILE)?;
+    let mut stmt = conn.prepare(
+        "SELECT target, name, created_at, updated_at
+         FROM target_secrets WHERE target = ?1 ORDER BY name"
+    )?;
+
+    let secrets = stmt.query_map(params![target], secret_info_from_row)?
+        .collect::<SqlResult<Vec<_>>>()?;
+
+    Ok(secrets)
+}
+
+/// Deletes a target's secret. Returns whether there was one.
+pub fn delete_target_secret(target: &str, name: &str) -> SqlResult<bool> {
+    let conn = Connection::open(DATABASE_FILE)?;
+    let deleted = conn.execute(
+        "DELETE FROM target_secrets WHERE target = ?1 AND name = ?2",
+        params![target, name],
+    )?;
+
+    Ok(deleted > 0)
+}
+
+fn secret_info_from_row(row: &rusqlite::Row) -> SqlResult<SecretInfo> {
+    Ok(SecretInfo {
+        target: row.get(0)?,
+        name: row.get(1)?,
+        created_at: row.get(2)?,
+        updated_at: row.get(3)?,
+    })
+}
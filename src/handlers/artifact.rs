@@ -0,0 +1,502 @@
+use actix_multipart::Multipart;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use actix_web::http::header::{
+    self, ContentDisposition, DispositionParam, DispositionType, EntityTag, HeaderName, HeaderValue,
+};
+use actix_web::web::Bytes;
+use futures_util::{stream, Stream, StreamExt};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::models::job::{ArtifactInfo, InputArtifact, JobArtifact, JobStatus};
+use crate::models::pipeline::Job;
+use crate::db::operations::{
+    get_job_run,
+    get_succeeded_job_artifacts,
+    get_run_source,
+    save_job_artifact,
+    get_job_artifact,
+    get_job_artifacts,
+    get_run_artifacts,
+    get_latest_successful_run,
+    get_target,
+};
+use crate::storage::artifacts::{self, BlobWriter, TEXT_MEDIA_TYPE};
+
+// Media type of uploads that don't say what they are
+const DEFAULT_MEDIA_TYPE: &str = "application/octet-stream";
+
+// How much of an artifact is read at a time when sending it
+const READ_SIZE: usize = 64 * 1024;
+
+// Names the run a latest artifact was taken from
+const RUN_ID_HEADER: &str = "x-pipeline-run-id";
+
+// Characters left as they are in download URLs. Slashes separate the parts
+// of an artifact's name.
+const URL_PATH: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~')
+    .remove(b'/');
+
+/// Stores an artifact reported as text with a job's result or update.
+pub fn save_reported_artifact(job_run_id: &str, artifact: &JobArtifact) -> Result<ArtifactInfo, String> {
+    if !is_valid_name(&artifact.name) {
+        return Err(format!("Invalid artifact name '{}'", artifact.name));
+    }
+
+    let blob = artifacts::store(artifact.content.as_bytes())
+        .map_err(|e| format!("Failed to store artifact {}: {}", artifact.name, e))?;
+    save_job_artifact(job_run_id, &artifact.name, &blob, TEXT_MEDIA_TYPE)
+        .map_err(|e| format!("Failed to save artifact {}: {}", artifact.name, e))
+}
+
+/// Hands a job the artifacts of the jobs it depends on. Inputs with
+/// `from_output` get the output's download URL as their value, and the
+/// artifacts of the jobs in `artifacts_from` are listed for the worker to
+/// fetch.
+pub fn resolve_artifacts(pipeline_run_id: &str, job: &mut Job) -> Result<Vec<InputArtifact>, String> {
+    let mut upstream = HashMap::new();
+
+    for input in &mut job.inputs {
+        let Some(source) = &input.from_output else {
+            continue;
+        };
+        let (job_run_id, artifacts) = upstream_artifacts(&mut upstream, pipeline_run_id, &source.job)?;
+        if !artifacts.iter().any(|artifact| artifact.name == source.output) {
+            return Err(format!(
+                "Job '{}' has no output '{}' for input '{}'",
+                source.job, source.output, input.name
+            ));
+        }
+        input.value = download_url(job_run_id, &source.output);
+    }
+
+    let mut inputs = Vec::new();
+    for name in &job.artifacts_from {
+        let (job_run_id, artifacts) = upstream_artifacts(&mut upstream, pipeline_run_id, name)?;
+        inputs.extend(artifacts.iter().map(|artifact| InputArtifact {
+            job: name.clone(),
+            name: artifact.name.clone(),
+            url: download_url(job_run_id, &artifact.name),
+            size: artifact.size,
+            sha256: artifact.sha256.clone(),
+        }));
+    }
+
+    Ok(inputs)
+}
+
+// The artifacts of an upstream job's successful attempt, looked up once
+fn upstream_artifacts<'a>(
+    upstream: &'a mut HashMap<String, (String, Vec<ArtifactInfo>)>,
+    pipeline_run_id: &str,
+    job_name: &str,
+) -> Result<&'a (String, Vec<ArtifactInfo>), String> {
+    if !upstream.contains_key(job_name) {
+        let found = get_succeeded_job_artifacts(pipeline_run_id, job_name)
+            .map_err(|e| format!("Failed to get artifacts of job '{}': {}", job_name, e))?
+            .ok_or_else(|| format!("Job '{}' has not succeeded", job_name))?;
+        upstream.insert(job_name.to_string(), found);
+    }
+    Ok(&upstream[job_name])
+}
+
+fn download_url(job_run_id: &str, name: &str) -> String {
+    format!("/api/jobs/{}/artifacts/{}", job_run_id, utf8_percent_encode(name, URL_PATH))
+}
+
+/// The outputs a job declares that it has not uploaded.
+pub fn missing_outputs(job_run_id: &str, job: &Job) -> Result<Vec<String>, String> {
+    if job.outputs.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let artifacts = get_job_artifacts(job_run_id)
+        .map_err(|e| format!("Failed to get artifacts: {}", e))?;
+    Ok(job.outputs.iter()
+        .filter(|output| !artifacts.iter().any(|artifact| artifact.name == output.name))
+        .map(|output| output.name.clone())
+        .collect())
+}
+
+// Artifact names are relative paths such as `dist/app.whl`
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.contains(['\\', '\0'])
+        && name.split('/').all(|part| !part.is_empty() && part != "." && part != "..")
+}
+
+// The response to send if artifacts can't be uploaded for the job, which
+// is once it is no longer running
+fn check_running(job_id: &str) -> Option<HttpResponse> {
+    match get_job_run(job_id) {
+        Ok(Some(job_run)) if job_run.status != JobStatus::Running => Some(HttpResponse::Conflict()
+            .json(json!({
+                "error": format!("Job is not running ({})", job_run.status)
+            }))),
+        Ok(Some(_)) => None,
+        Ok(None) => Some(HttpResponse::NotFound()
+            .json(json!({
+                "error": "Job not found"
+            }))),
+        Err(e) => Some(HttpResponse::InternalServerError()
+            .json(json!({
+                "error": format!("Failed to get job: {}", e)
+            }))),
+    }
+}
+
+fn upload_failed(name: &str, e: impl std::fmt::Display) -> HttpResponse {
+    HttpResponse::InternalServerError()
+        .json(json!({
+            "error": format!("Failed to store artifact {}: {}", name, e)
+        }))
+}
+
+// Upload an artifact of a running job as the raw request body, with its
+// media type as the Content-Type. Workers call this before reporting the
+// job's result.
+pub async fn upload_artifact(
+    request: HttpRequest,
+    path: web::Path<(String, String)>,
+    mut body: web::Payload,
+) -> impl Responder {
+    let (job_id, name) = path.into_inner();
+    if let Some(response) = check_running(&job_id) {
+        return response;
+    }
+    if !is_valid_name(&name) {
+        return HttpResponse::BadRequest()
+            .json(json!({
+                "error": format!("Invalid artifact name '{}'", name)
+            }));
+    }
+
+    let media_type = request.headers().get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or(DEFAULT_MEDIA_TYPE)
+        .to_string();
+
+    let mut writer = match BlobWriter::new() {
+        Ok(writer) => writer,
+        Err(e) => return upload_failed(&name, e),
+    };
+    while let Some(chunk) = body.next().await {
+        let written = match chunk {
+            Ok(chunk) => writer.write(&chunk),
+            Err(e) => return HttpResponse::BadRequest()
+                .json(json!({
+                    "error": format!("Failed to read artifact {}: {}", name, e)
+                })),
+        };
+        if let Err(e) = written {
+            return upload_failed(&name, e);
+        }
+    }
+
+    let blob = match writer.finish() {
+        Ok(blob) => blob,
+        Err(e) => return upload_failed(&name, e),
+    };
+    match save_job_artifact(&job_id, &name, &blob, &media_type) {
+        Ok(artifact) => HttpResponse::Ok().json(artifact),
+        Err(e) => upload_failed(&name, e),
+    }
+}
+
+// Upload artifacts of a running job as `multipart/form-data`. Each file is
+// saved under its file name, or its field name if it has none.
+pub async fn upload_artifacts(
+    job_id: web::Path<String>,
+    mut form: Multipart,
+) -> impl Responder {
+    if let Some(response) = check_running(&job_id) {
+        return response;
+    }
+
+    let mut saved = Vec::new();
+    while let Some(field) = form.next().await {
+        let mut field = match field {
+            Ok(field) => field,
+            Err(e) => return HttpResponse::BadRequest()
+                .json(json!({
+                    "error": format!("Invalid multipart upload: {}", e)
+                })),
+        };
+
+        let name = field.content_disposition()
+            .and_then(|disposition| disposition.get_filename().or(disposition.get_name()))
+            .unwrap_or_default()
+            .to_string();
+        if !is_valid_name(&name) {
+            return HttpResponse::BadRequest()
+                .json(json!({
+                    "error": format!("Invalid artifact name '{}'", name)
+                }));
+        }
+        let media_type = field.content_type()
+            .map_or(DEFAULT_MEDIA_TYPE.to_string(), |mime| mime.to_string());
+
+        let mut writer = match BlobWriter::new() {
+            Ok(writer) => writer,
+            Err(e) => return upload_failed(&name, e),
+        };
+        while let Some(chunk) = field.next().await {
+            let written = match chunk {
+                Ok(chunk) => writer.write(&chunk),
+                Err(e) => return HttpResponse::BadRequest()
+                    .json(json!({
+                        "error": format!("Failed to read artifact {}: {}", name, e)
+                    })),
+            };
+            if let Err(e) = written {
+                return upload_failed(&name, e);
+            }
+        }
+
+        let blob = match writer.finish() {
+            Ok(blob) => blob,
+            Err(e) => return upload_failed(&name, e),
+        };
+        match save_job_artifact(&job_id, &name, &blob, &media_type) {
+            Ok(artifact) => saved.push(artifact),
+            Err(e) => return upload_failed(&name, e),
+        }
+    }
+
+    HttpResponse::Ok().json(saved)
+}
+
+// Reads a file in pieces as it is sent
+fn file_stream(file: File) -> impl Stream<Item = Result<Bytes, actix_web::Error>> {
+    stream::unfold(Some(file), |file| async move {
+        let mut file = file?;
+        let mut buffer = vec![0; READ_SIZE];
+        match file.read(&mut buffer) {
+            Ok(0) => None,
+            Ok(length) => {
+                buffer.truncate(length);
+                Some((Ok(Bytes::from(buffer)), Some(file)))
+            }
+            Err(e) => Some((Err(actix_web::error::ErrorInternalServerError(e)), None)),
+        }
+    })
+}
+
+// Sends an artifact's content, as a download named after the artifact
+fn serve_artifact(request: &HttpRequest, artifact: &ArtifactInfo) -> HttpResponse {
+    // Content never changes under a checksum
+    let etag = EntityTag::new_strong(artifact.sha256.clone());
+    let cached = request.headers().get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<EntityTag>().ok())
+        .is_some_and(|tag| tag.strong_eq(&etag));
+    if cached {
+        return HttpResponse::NotModified().insert_header(header::ETag(etag)).finish();
+    }
+
+    let file = match artifacts::open(&artifact.sha256) {
+        Ok(file) => file,
+        Err(e) => return HttpResponse::InternalServerError()
+            .json(json!({
+                "error": format!("Failed to read artifact {}: {}", artifact.name, e)
+            })),
+    };
+    let file_name = artifact.name.rsplit('/').next().unwrap_or(&artifact.name);
+
+    HttpResponse::Ok()
+        .content_type(artifact.media_type.as_str())
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(file_name.to_string())],
+        })
+        .insert_header(header::ETag(etag))
+        .no_chunking(artifact.size)
+        .streaming(file_stream(file))
+}
+
+// List a job's artifacts with their size and checksum
+pub async fn list_artifacts(job_id: web::Path<String>) -> impl Responder {
+    match get_job_run(&job_id) {
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::NotFound()
+            .json(json!({
+                "error": "Job not found"
+            })),
+        Err(e) => return HttpResponse::InternalServerError()
+            .json(json!({
+                "error": format!("Failed to get job: {}", e)
+            })),
+    }
+
+    match get_job_artifacts(&job_id) {
+        Ok(artifacts) => HttpResponse::Ok().json(artifacts),
+        Err(e) => HttpResponse::InternalServerError()
+            .json(json!({
+                "error": format!("Failed to get artifacts: {}", e)
+            })),
+    }
+}
+
+// Download one of a job's artifacts
+pub async fn download_artifact(
+    request: HttpRequest,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (job_id, name) = path.into_inner();
+
+    match get_job_artifact(&job_id, &name) {
+        Ok(Some(artifact)) => serve_artifact(&request, &artifact),
+        Ok(None) => HttpResponse::NotFound()
+            .json(json!({
+                "error": format!("Artifact '{}' not found", name)
+            })),
+        Err(e) => HttpResponse::InternalServerError()
+            .json(json!({
+                "error": format!("Failed to get artifact: {}", e)
+            })),
+    }
+}
+
+// Download every artifact of a run as a zip file, with each job's artifacts
+// in a folder named after the job. Jobs that ran more than once contribute
+// the artifacts of their latest attempt.
+pub async fn download_run_artifacts(run_id: web::Path<String>) -> impl Responder {
+    match get_run_source(&run_id) {
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::NotFound()
+            .json(json!({
+                "error": "Pipeline run not found"
+            })),
+        Err(e) => return HttpResponse::InternalServerError()
+            .json(json!({
+                "error": format!("Failed to get pipeline run: {}", e)
+            })),
+    }
+
+    let artifacts = match get_run_artifacts(&run_id) {
+        Ok(artifacts) => artifacts,
+        Err(e) => return HttpResponse::InternalServerError()
+            .json(json!({
+                "error": format!("Failed to get artifacts: {}", e)
+            })),
+    };
+
+    let archive = match web::block(move || zip_artifacts(&artifacts)).await {
+        Ok(Ok(archive)) => archive,
+        Ok(Err(e)) => return HttpResponse::InternalServerError()
+            .json(json!({
+                "error": format!("Failed to create archive: {}", e)
+            })),
+        Err(e) => return HttpResponse::InternalServerError()
+            .json(json!({
+                "error": format!("Failed to create archive: {}", e)
+            })),
+    };
+
+    HttpResponse::Ok()
+        .content_type("application/zip")
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(format!("{}-artifacts.zip", run_id))],
+        })
+        .streaming(file_stream(archive))
+}
+
+// Writes the archive to a temporary file, so large artifacts aren't held
+// in memory. The file is gone once the returned handle is closed.
+fn zip_artifacts(artifacts: &[(String, ArtifactInfo)]) -> Result<File, String> {
+    let file = tempfile::tempfile().map_err(|e| e.to_string())?;
+    let mut archive = ZipWriter::new(file);
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .large_file(true);
+
+    for (job_name, artifact) in artifacts {
+        let mut content = artifacts::open(&artifact.sha256)
+            .map_err(|e| format!("Failed to read artifact {}: {}", artifact.name, e))?;
+        archive.start_file(format!("{}/{}", job_name.replace('/', "_"), artifact.name), options)
+            .map_err(|e| e.to_string())?;
+        io::copy(&mut content, &mut archive).map_err(|e| e.to_string())?;
+    }
+
+    let mut file = archive.finish().map_err(|e| e.to_string())?;
+    file.seek(SeekFrom::Start(0)).map_err(|e| e.to_string())?;
+    Ok(file)
+}
+
+#[derive(Deserialize)]
+pub struct LatestArtifactQuery {
+    /// Job the artifact comes from, when several jobs have one of that name.
+    pub job: Option<String>,
+}
+
+// Download an artifact from the target's latest successful run, e.g. for
+// deploy scripts that want the current build of a branch
+pub async fn download_latest_artifact(
+    request: HttpRequest,
+    path: web::Path<(String, String)>,
+    query: web::Query<LatestArtifactQuery>,
+) -> impl Responder {
+    let (target_name, name) = path.into_inner();
+
+    let target = match get_target(&target_name) {
+        Ok(Some(target)) => target,
+        Ok(None) => return HttpResponse::NotFound()
+            .json(json!({
+                "error": format!("Target '{}' not found", target_name)
+            })),
+        Err(e) => return HttpResponse::InternalServerError()
+            .json(json!({
+                "error": format!("Failed to get target: {}", e)
+            })),
+    };
+
+    let run_id = match get_latest_successful_run(&target.repository, &target.branch) {
+        Ok(Some(run_id)) => run_id,
+        Ok(None) => return HttpResponse::NotFound()
+            .json(json!({
+                "error": format!("Target '{}' has no successful run", target_name)
+            })),
+        Err(e) => return HttpResponse::InternalServerError()
+            .json(json!({
+                "error": format!("Failed to get latest run: {}", e)
+            })),
+    };
+
+    let artifact = match get_run_artifacts(&run_id) {
+        Ok(artifacts) => artifacts.into_iter()
+            .filter(|(job_name, artifact)| {
+                artifact.name == name && query.job.as_ref().is_none_or(|job| job == job_name)
+            })
+            .map(|(_, artifact)| artifact)
+            .next_back(),
+        Err(e) => return HttpResponse::InternalServerError()
+            .json(json!({
+                "error": format!("Failed to get artifacts: {}", e)
+            })),
+    };
+
+    match artifact {
+        Some(artifact) => {
+            let mut response = serve_artifact(&request, &artifact);
+            if let Ok(value) = HeaderValue::from_str(&run_id) {
+                response.headers_mut().insert(HeaderName::from_static(RUN_ID_HEADER), value);
+            }
+            response
+        }
+        None => HttpResponse::NotFound()
+            .json(json!({
+                "error": format!("Artifact '{}' not found in run {}", name, run_id)
+            })),
+    }
+}
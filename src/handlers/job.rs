@@ -3,21 +3,16 @@ use serde_json::json;
 use chrono::{DateTime, Utc};
 use rusqlite::{Connection, params};
 
-use crate::models::job::{JobStatus, JobResult, JobArtifact};
+use crate::models::job::{JobStatus, JobResult, JobArtifact, ArtifactInfo};
 use crate::db::operations::{
     update_job_status,
-    save_job_artifact,
+    get_job_artifacts,
+    get_job_run,
+    parse_status,
 };
-
-// Helper functions for datetime conversion (matching db/operations.rs)
-fn datetime_to_sqlite(dt: DateTime<Utc>) -> String {
-    dt.format("%Y-%m-%d %H:%M:%S").to_string()
-}
-
-fn sqlite_to_datetime(s: String) -> Result<DateTime<Utc>, chrono::ParseError> {
-    DateTime::parse_from_str(&format!("{} +0000", s), "%Y-%m-%d %H:%M:%S %z")
-        .map(|dt| dt.with_timezone(&Utc))
-}
+use crate::handlers::artifact::save_reported_artifact;
+use crate::handlers::pipeline::PendingResults;
+use crate::storage::logs;
 
 #[derive(serde::Deserialize)]
 pub struct JobUpdate {
@@ -32,73 +27,112 @@ pub struct JobDetails {
     pub pipeline_run_id: String,
     pub name: String,
     pub index: i32,
+    pub attempt: i32,
     pub status: JobStatus,
+    pub worker_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_reason: Option<String>,
     pub start_time: DateTime<Utc>,
     pub end_time: Option<DateTime<Utc>>,
     pub duration_seconds: Option<i64>,
-    pub output: String,
-    pub artifacts: Vec<JobArtifact>,
+    /// Size of the job's log in bytes, see `GET /api/jobs/{id}/logs`.
+    pub log_size: u64,
+    pub artifacts: Vec<ArtifactInfo>,
 }
 
-// Update job status and optionally add artifacts
+// Update job status and optionally add artifacts. Workers call this to
+// report progress and, for jobs they accepted without a result, the outcome.
 pub async fn update_job(
     job_id: web::Path<String>,
     update: web::Json<JobUpdate>,
+    pending_results: web::Data<PendingResults>,
 ) -> impl Responder {
-    // Update job status
-    if let Err(e) = update_job_status(
-        &job_id,
-        update.status.clone(),
-        update.output.as_deref(),
-    ) {
+    // Only the master puts jobs back in the queue
+    if update.status != JobStatus::Running && !update.status.is_finished() {
+        return HttpResponse::BadRequest()
+            .json(json!({
+                "error": format!("'{}' is not a status a worker can report", update.status)
+            }));
+    }
+
+    match get_job_run(&job_id) {
+        Ok(Some(job_run)) if job_run.status.is_finished() => return HttpResponse::Conflict()
+            .json(json!({
+                "error": format!("Job has already finished ({})", job_run.status)
+            })),
+        // e.g. a worker whose lease on the job expired reporting late
+        Ok(Some(job_run)) if job_run.status != JobStatus::Running => return HttpResponse::Conflict()
+            .json(json!({
+                "error": format!("Job is not running ({})", job_run.status)
+            })),
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::NotFound()
+            .json(json!({
+                "error": "Job not found"
+            })),
+        Err(e) => return HttpResponse::InternalServerError()
+            .json(json!({
+                "error": format!("Failed to get job: {}", e)
+            })),
+    }
+
+    if let Err(e) = record_job_update(&job_id, &update, &pending_results) {
         return HttpResponse::InternalServerError()
             .json(json!({
                 "error": format!("Failed to update job status: {}", e)
             }));
     }
 
+    HttpResponse::Ok()
+        .json(json!({
+            "status": "updated",
+            "job_id": job_id.to_string()
+        }))
+}
+
+/// Saves a worker's report on a running job. A final result goes to the
+/// executor waiting for it, which decides about retries; if none is
+/// waiting it is recorded directly.
+pub fn record_job_update(
+    job_id: &str,
+    update: &JobUpdate,
+    pending_results: &PendingResults,
+) -> rusqlite::Result<()> {
     // If artifacts are provided, save them
     if let Some(artifacts) = &update.artifacts {
         for artifact in artifacts {
-            if let Err(e) = save_job_artifact(
-                &job_id,
-                &artifact.name,
-                &artifact.content,
-            ) {
-                eprintln!("Failed to save artifact {}: {}", artifact.name, e);
+            if let Err(e) = save_reported_artifact(job_id, artifact) {
+                eprintln!("{}", e);
             }
         }
     }
 
-    HttpResponse::Ok()
-        .json(json!({
-            "status": "updated",
-            "job_id": job_id.to_string()
-        }))
+    let delivered = update.status.is_finished() && pending_results.deliver(JobResult {
+        id: job_id.to_string(),
+        status: update.status.clone(),
+        output: update.output.clone().unwrap_or_default(),
+        artifacts: vec![],
+        note: None,
+    }).is_ok();
+
+    if !delivered {
+        update_job_status(job_id, update.status.clone(), update.output.as_deref())?;
+    }
+
+    Ok(())
 }
 
 // Get job details including artifacts
 pub async fn get_job_details(job_id: web::Path<String>) -> impl Responder {
     match get_job_with_artifacts(&job_id) {
         Ok(job_details) => HttpResponse::Ok().json(job_details),
-        Err(e) => HttpResponse::InternalServerError()
+        Err(rusqlite::Error::QueryReturnedNoRows) => HttpResponse::NotFound()
             .json(json!({
-                "error": format!("Failed to get job details: {}", e)
-            }))
-    }
-}
-
-// Get logs for a specific job
-pub async fn get_job_logs(job_id: web::Path<String>) -> impl Responder {
-    match get_job_output(&job_id) {
-        Ok(Some(output)) => HttpResponse::Ok().body(output),
-        Ok(None) => HttpResponse::NotFound()
-            .json(json!({
-                "error": "No logs found for this job"
+                "error": "Job not found"
             })),
         Err(e) => HttpResponse::InternalServerError()
             .json(json!({
-                "error": format!("Failed to get job logs: {}", e)
+                "error": format!("Failed to get job details: {}", e)
             }))
     }
 }
@@ -111,13 +145,11 @@ fn get_job_with_artifacts(job_id: &str) -> Result<JobDetails, rusqlite::Error> {
     // Get job information
     let job = conn.query_row(
         "SELECT id, pipeline_run_id, job_name, job_index, status,
-                start_time, end_time, duration_seconds, output
+                start_time, end_time, duration_seconds, attempt, worker_id, queue_reason
          FROM job_runs 
          WHERE id = ?1",
         params![job_id],
         |row| {
-            let start_time: String = row.get(5)?;
-            let end_time: Option<String> = row.get(6)?;
             let status: String = row.get(4)?;
 
             Ok(JobDetails {
@@ -125,43 +157,24 @@ fn get_job_with_artifacts(job_id: &str) -> Result<JobDetails, rusqlite::Error> {
                 pipeline_run_id: row.get(1)?,
                 name: row.get(2)?,
                 index: row.get(3)?,
-                status: serde_json::from_str(&status).unwrap(),
-                start_time: sqlite_to_datetime(start_time).unwrap(),
-                end_time: end_time.map(|t| sqlite_to_datetime(t).unwrap()),
+                attempt: row.get(8)?,
+                status: parse_status(status)?,
+                worker_id: row.get(9)?,
+                queue_reason: row.get(10)?,
+                start_time: row.get(5)?,
+                end_time: row.get(6)?,
                 duration_seconds: row.get(7)?,
-                output: row.get(8)?,
+                log_size: 0, // Read from the log store below
                 artifacts: Vec::new(), // Will be populated below
             })
         }
     )?;
 
     // Get artifacts for this job
-    let mut stmt = conn.prepare(
-        "SELECT name, content
-         FROM job_artifacts 
-         WHERE job_run_id = ?1 
-         ORDER BY created_at"
-    )?;
+    let artifacts = get_job_artifacts(job_id)?;
 
-    let artifacts = stmt.query_map(params![job_id], |row| {
-        Ok(JobArtifact {
-            name: row.get(0)?,
-            content: row.get(1)?,
-        })
-    })?
-    .collect::<Result<Vec<_>, _>>()?;
+    let log_size = logs::size(job_id)
+        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
 
-    Ok(JobDetails { artifacts, ..job })
-}
-
-// Internal helper function to get job output
-fn get_job_output(job_id: &str) -> Result<Option<String>, rusqlite::Error> {
-    use crate::db::init::DATABASE_FILE;
-    let conn = Connection::open(DATABASE_FILE)?;
-    
-    conn.query_row(
-        "SELECT output FROM job_runs WHERE id = ?1",
-        params![job_id],
-        |row| row.get(0)
-    )
+    Ok(JobDetails { artifacts, log_size, ..job })
 }
\ No newline at end of file
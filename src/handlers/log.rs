@@ -0,0 +1,385 @@
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use actix_web::http::header;
+use actix_web::web::Bytes;
+use futures_util::{stream, StreamExt};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::models::job::JobStatus;
+use crate::db::operations::{get_job_run, mark_log_streamed};
+use crate::storage::logs;
+use crate::utils::secrets;
+
+/// Live log streams, by job id, that appended log chunks are sent to.
+#[derive(Default)]
+pub struct LogStreams(Mutex<HashMap<String, broadcast::Sender<LogChunk>>>);
+
+#[derive(Clone)]
+pub struct LogChunk {
+    /// Byte offset of the chunk in the job's log.
+    offset: u64,
+    text: String,
+}
+
+// Chunks a slow stream may fall behind by before it re-reads the log
+const LOG_STREAM_CAPACITY: usize = 256;
+
+// How often a stream checks whether its job has finished
+const STATUS_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Most of the stored log a stream sends in one event
+const CATCH_UP_SIZE: u64 = 1024 * 1024;
+
+impl LogStreams {
+    fn subscribe(&self, job_id: &str) -> broadcast::Receiver<LogChunk> {
+        self.0.lock().unwrap()
+            .entry(job_id.to_string())
+            .or_insert_with(|| broadcast::channel(LOG_STREAM_CAPACITY).0)
+            .subscribe()
+    }
+
+    fn unsubscribe(&self, job_id: &str) {
+        let mut streams = self.0.lock().unwrap();
+        if streams.get(job_id).is_some_and(|sender| sender.receiver_count() == 0) {
+            streams.remove(job_id);
+        }
+    }
+
+    fn publish(&self, job_id: &str, chunk: LogChunk) {
+        if let Some(sender) = self.0.lock().unwrap().get(job_id) {
+            let _ = sender.send(chunk);
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LogQuery {
+    /// Byte offset to start from.
+    pub offset: Option<u64>,
+    /// Number of lines to return from the end of the log.
+    pub tail: Option<u64>,
+}
+
+// Get a job's log. A `Range` header, `?offset=` or `?tail=N` selects part of
+// it, and `X-Log-Offset` tells where the returned part starts so a client
+// can carry on from there, e.g. with `Last-Event-ID` on the stream.
+pub async fn get_job_logs(
+    request: HttpRequest,
+    job_id: web::Path<String>,
+    query: web::Query<LogQuery>,
+) -> impl Responder {
+    match get_job_run(&job_id) {
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::NotFound()
+            .json(json!({
+                "error": "Job not found"
+            })),
+        Err(e) => return HttpResponse::InternalServerError()
+            .json(json!({
+                "error": format!("Failed to get job: {}", e)
+            })),
+    }
+
+    let chunks = match logs::chunks(&job_id) {
+        Ok(chunks) => chunks,
+        Err(e) => return HttpResponse::InternalServerError()
+            .json(json!({
+                "error": format!("Failed to get job logs: {}", e)
+            })),
+    };
+    let size = chunks.last().map_or(0, |chunk| chunk.offset + chunk.length);
+
+    let range = request.headers().get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        // Several ranges at once aren't supported, the whole log is sent instead
+        .filter(|value| !value.contains(','))
+        .map(|value| parse_range(value, size));
+
+    let (start, end) = match (range, query.tail) {
+        (Some(Some(range)), _) => range,
+        (Some(None), _) => return HttpResponse::RangeNotSatisfiable()
+            .insert_header((header::CONTENT_RANGE, format!("bytes */{}", size)))
+            .json(json!({
+                "error": format!("Range not satisfiable, the log is {} bytes", size)
+            })),
+        (None, Some(lines)) => match logs::tail_offset(&chunks, lines) {
+            Ok(start) => (start, size),
+            Err(e) => return HttpResponse::InternalServerError()
+                .json(json!({
+                    "error": format!("Failed to get job logs: {}", e)
+                })),
+        },
+        (None, None) => (query.offset.unwrap_or(0).min(size), size),
+    };
+
+    let body = stream::iter(chunks).filter_map(move |chunk| async move {
+        logs::read_part(&chunk, start, end)
+            .map_err(actix_web::error::ErrorInternalServerError)
+            .transpose()
+            .map(|part| part.map(Bytes::from))
+    });
+
+    let mut response = if range.is_some() {
+        let mut response = HttpResponse::PartialContent();
+        response.insert_header((
+            header::CONTENT_RANGE,
+            format!("bytes {}-{}/{}", start, end - 1, size),
+        ));
+        response
+    } else {
+        HttpResponse::Ok()
+    };
+
+    response
+        .content_type("text/plain; charset=utf-8")
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+        .insert_header(("X-Log-Offset", start.to_string()))
+        .no_chunking(end - start)
+        .streaming(body)
+}
+
+// Parses a single `bytes=` range into offsets `start..end` of a log of
+// `size` bytes. Returns `None` if the range is unsatisfiable.
+fn parse_range(value: &str, size: u64) -> Option<(u64, u64)> {
+    let (first, last) = value.strip_prefix("bytes=")?.trim().split_once('-')?;
+    let (start, end) = match (first.trim(), last.trim()) {
+        ("", suffix) => (size.saturating_sub(suffix.parse().ok()?), size),
+        (first, "") => (first.parse().ok()?, size),
+        (first, last) => (first.parse().ok()?, last.parse::<u64>().ok()?.saturating_add(1).min(size)),
+    };
+    (start < end).then_some((start, end))
+}
+
+// Append a chunk of output to a running job's log. Workers call this while
+// the job runs, and the chunk reaches live log streams right away.
+pub async fn append_job_log(
+    job_id: web::Path<String>,
+    chunk: String,
+    log_streams: web::Data<LogStreams>,
+) -> impl Responder {
+    match get_job_run(&job_id) {
+        Ok(Some(job_run)) if job_run.status != JobStatus::Running => return HttpResponse::Conflict()
+            .json(json!({
+                "error": format!("Job is not running ({})", job_run.status)
+            })),
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::NotFound()
+            .json(json!({
+                "error": "Job not found"
+            })),
+        Err(e) => return HttpResponse::InternalServerError()
+            .json(json!({
+                "error": format!("Failed to get job: {}", e)
+            })),
+    }
+
+    // Output reported with the job's status no longer replaces the log
+    if let Err(e) = mark_log_streamed(&job_id) {
+        return HttpResponse::InternalServerError()
+            .json(json!({
+                "error": format!("Failed to update job: {}", e)
+            }));
+    }
+
+    let chunk = secrets::mask_chunk(&job_id, &chunk);
+    let offset = match logs::append(&job_id, &chunk) {
+        Ok(offset) => offset,
+        Err(e) => return HttpResponse::InternalServerError()
+            .json(json!({
+                "error": format!("Failed to append to job log: {}", e)
+            })),
+    };
+    let length = offset + chunk.len() as u64;
+    if !chunk.is_empty() {
+        log_streams.publish(&job_id, LogChunk { offset, text: chunk });
+    }
+
+    HttpResponse::Ok()
+        .json(json!({
+            "job_id": job_id.to_string(),
+            "length": length
+        }))
+}
+
+// Stream a job's log as Server-Sent Events: what has been logged so far,
+// then each chunk as it arrives, and an `end` event with the job's final
+// status. Each `log` event's id is the log's length after it, so a client
+// reconnecting with `Last-Event-ID` picks up where it left off.
+pub async fn stream_job_logs(
+    request: HttpRequest,
+    job_id: web::Path<String>,
+    log_streams: web::Data<LogStreams>,
+) -> impl Responder {
+    match get_job_run(&job_id) {
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::NotFound()
+            .json(json!({
+                "error": "Job not found"
+            })),
+        Err(e) => return HttpResponse::InternalServerError()
+            .json(json!({
+                "error": format!("Failed to get job: {}", e)
+            })),
+    }
+
+    let offset = request.headers().get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0);
+
+    // Subscribe before reading the stored log so no chunk falls in between
+    let chunks = log_streams.subscribe(&job_id);
+    let (events, receiver) = mpsc::channel(16);
+    tokio::spawn(follow_job_log(job_id.into_inner(), offset, chunks, events, log_streams));
+
+    let body = stream::unfold(receiver, |mut receiver| async move {
+        let event = receiver.recv().await?;
+        Some((Ok::<_, actix_web::Error>(event), receiver))
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(body)
+}
+
+// Feeds a job's log stream until the job finishes or the client goes away
+async fn follow_job_log(
+    job_id: String,
+    offset: u64,
+    mut chunks: broadcast::Receiver<LogChunk>,
+    events: mpsc::Sender<Bytes>,
+    log_streams: web::Data<LogStreams>,
+) {
+    let mut status_poll = tokio::time::interval(STATUS_POLL_INTERVAL);
+    let mut offset = catch_up(&job_id, offset, &events).await;
+
+    while let Some(sent) = offset {
+        offset = tokio::select! {
+            chunk = chunks.recv() => match chunk {
+                Ok(chunk) if chunk.offset <= sent => {
+                    let end = chunk.offset + chunk.text.len() as u64;
+                    match chunk.text.get((sent - chunk.offset) as usize..) {
+                        // Already sent while catching up
+                        _ if end <= sent => Some(sent),
+                        // Only the part after a Last-Event-ID or catch-up
+                        // that ended inside the chunk is new
+                        Some(rest) => send(&events, log_event(rest, end)).await.then_some(end),
+                        // The offset is inside a character, so read it from the store
+                        None => catch_up(&job_id, sent, &events).await,
+                    }
+                }
+                // Chunks went missing, e.g. because this stream fell behind
+                _ => catch_up(&job_id, sent, &events).await,
+            },
+            _ = status_poll.tick() => match get_job_run(&job_id) {
+                Ok(Some(job_run)) if job_run.status.is_finished() => {
+                    // The final result can carry output that was never streamed
+                    if catch_up(&job_id, sent, &events).await.is_some() {
+                        let end = format!("event: end\ndata: {}\n\n", job_run.status);
+                        send(&events, Bytes::from(end)).await;
+                    }
+                    None
+                }
+                Ok(Some(_)) => Some(sent),
+                Ok(None) => None,
+                Err(e) => {
+                    eprintln!("Failed to get job {}: {}", job_id, e);
+                    None
+                }
+            },
+        };
+    }
+
+    drop(chunks);
+    log_streams.unsubscribe(&job_id);
+}
+
+// Sends the stored log from `offset` on. Returns the new offset, or `None`
+// if the stream should stop.
+async fn catch_up(job_id: &str, mut offset: u64, events: &mpsc::Sender<Bytes>) -> Option<u64> {
+    loop {
+        let mut output = match logs::read(job_id, offset, offset + CATCH_UP_SIZE) {
+            Ok(output) => output,
+            Err(e) => {
+                eprintln!("Failed to read log of job {}: {}", job_id, e);
+                return None;
+            }
+        };
+        let complete = (output.len() as u64) < CATCH_UP_SIZE;
+
+        // Leave a character cut off at the end for the next read
+        if let Err(e) = std::str::from_utf8(&output) {
+            if e.error_len().is_none() && e.valid_up_to() > 0 {
+                output.truncate(e.valid_up_to());
+            }
+        }
+        if output.is_empty() {
+            return Some(offset);
+        }
+
+        offset += output.len() as u64;
+        let text = String::from_utf8_lossy(&output);
+        if !send(events, log_event(&text, offset)).await {
+            return None;
+        }
+        if complete {
+            return Some(offset);
+        }
+    }
+}
+
+async fn send(events: &mpsc::Sender<Bytes>, event: Bytes) -> bool {
+    events.send(event).await.is_ok()
+}
+
+// One `data` field per line, which clients join back together with newlines
+fn log_event(text: &str, end: u64) -> Bytes {
+    let mut event = format!("event: log\nid: {}\n", end);
+    for line in text.split('\n') {
+        event.push_str("data: ");
+        event.push_str(line.trim_end_matches('\r'));
+        event.push('\n');
+    }
+    event.push('\n');
+    Bytes::from(event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_range_forms() {
+        assert_eq!(parse_range("bytes=0-9", 100), Some((0, 10)));
+        assert_eq!(parse_range("bytes=10-", 100), Some((10, 100)));
+        assert_eq!(parse_range("bytes=-20", 100), Some((80, 100)));
+        assert_eq!(parse_range("bytes= 5 - 6 ", 100), Some((5, 7)));
+    }
+
+    #[test]
+    fn clamps_ranges_to_the_log() {
+        assert_eq!(parse_range("bytes=90-500", 100), Some((90, 100)));
+        assert_eq!(parse_range("bytes=-500", 100), Some((0, 100)));
+        assert_eq!(parse_range(&format!("bytes=0-{}", u64::MAX), 100), Some((0, 100)));
+    }
+
+    #[test]
+    fn rejects_unsatisfiable_ranges() {
+        assert_eq!(parse_range("bytes=100-", 100), None);
+        assert_eq!(parse_range("bytes=5-4", 100), None);
+        assert_eq!(parse_range("bytes=-0", 100), None);
+        assert_eq!(parse_range("bytes=0-", 0), None);
+    }
+
+    #[test]
+    fn rejects_malformed_ranges() {
+        for value in ["items=0-9", "bytes=0", "bytes=a-9", "bytes=0-b", "bytes=0-1,5-6", "bytes=-"] {
+            assert_eq!(parse_range(value, 100), None, "{}", value);
+        }
+    }
+}
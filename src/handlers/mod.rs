@@ -1,3 +1,7 @@
+pub mod artifact;
 pub mod job;
+pub mod log;
 pub mod pipeline;
-pub mod target;
\ No newline at end of file
+pub mod secret;
+pub mod target;
+pub mod worker;
\ No newline at end of file
@@ -1,76 +1,684 @@
 use actix_web::{web, HttpResponse, Responder};
 use serde_yaml;
 use serde_json::json;
-use uuid::Uuid;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{oneshot, OwnedSemaphorePermit, Semaphore};
+use tokio::task::{AbortHandle, JoinSet};
 
-use crate::models::pipeline::{Job, Pipeline, PipelineStatus};
-use crate::models::target::BuildRequest;
-use crate::models::job::{JobStatus, JobResult};
+use crate::models::pipeline::{Job, Parameters, Pipeline, PipelineStatus};
+use crate::models::target::{BuildRequest, TriggerRequest};
+use crate::models::job::{JobStatus, JobResult, JobRequest};
 use crate::db::operations::{
     create_pipeline_run, create_job_run, 
-    update_job_status, get_pipeline_status
+    update_job_status, replace_job_log, append_job_note, get_pipeline_status,
+    get_pipeline_run_status, cancel_pipeline_run, time_out_pipeline_run,
+    get_run_source, get_job_run, get_latest_job_runs, count_job_runs,
+    begin_job_retry, get_job_worker, NewPipelineRun,
+    get_unfinished_pipeline_runs, interrupt_pipeline_run, update_pipeline_progress,
+    set_pipeline_run_pinned, get_target, get_target_for_branch,
 };
-use crate::utils::file;
+use crate::handlers::artifact::{missing_outputs, resolve_artifacts, save_reported_artifact};
+use crate::handlers::worker::{worker_url, Assignment, WorkerPool};
+use crate::storage::logs;
+use crate::utils::{duration, file, secrets};
 
-pub async fn trigger_build(build_request: web::Json<BuildRequest>) -> impl Responder {
+/// Server-wide cap on how many jobs may be running on workers at once,
+/// shared by every pipeline run.
+pub struct JobSlots(Arc<Semaphore>);
+
+impl JobSlots {
+    pub fn new(max_parallel: Option<usize>) -> Self {
+        JobSlots(Arc::new(Semaphore::new(permits(max_parallel))))
+    }
+}
+
+/// The slots a job of a run needs while it is on a worker: one of its
+/// pipeline's and one of the server's. Jobs only take them once a worker
+/// is there to run them, so jobs waiting for a worker don't hold slots
+/// that other jobs could use.
+#[derive(Clone)]
+pub struct Slots {
+    pipeline: Arc<Semaphore>,
+    global: Arc<Semaphore>,
+}
+
+/// A job's slots, freed when dropped.
+pub struct SlotPermits {
+    _pipeline: OwnedSemaphorePermit,
+    _global: OwnedSemaphorePermit,
+}
+
+impl Slots {
+    fn new(max_parallel: Option<usize>, job_slots: &JobSlots) -> Self {
+        Slots {
+            pipeline: Arc::new(Semaphore::new(permits(max_parallel))),
+            global: job_slots.0.clone(),
+        }
+    }
+
+    /// Takes both slots if they are free, or says what the job waits for.
+    /// The pipeline's slot comes first so a throttled pipeline doesn't
+    /// hold server-wide slots that other runs could use.
+    pub fn try_take(&self) -> Result<SlotPermits, String> {
+        let pipeline = self.pipeline.clone().try_acquire_owned()
+            .map_err(|_| "Waiting for the pipeline's max_parallel limit".to_string())?;
+        let global = self.global.clone().try_acquire_owned()
+            .map_err(|_| "Waiting for a free job slot (MAX_PARALLEL_JOBS)".to_string())?;
+        Ok(SlotPermits { _pipeline: pipeline, _global: global })
+    }
+
+    // Waits for both slots, for jobs that are on a worker already
+    async fn take(&self) -> SlotPermits {
+        let pipeline = self.pipeline.clone().acquire_owned().await
+            .expect("job slots are never closed");
+        let global = self.global.clone().acquire_owned().await
+            .expect("job slots are never closed");
+        SlotPermits { _pipeline: pipeline, _global: global }
+    }
+}
+
+// An unset cap means unlimited; a cap of zero would deadlock, so treat it as one.
+fn permits(max_parallel: Option<usize>) -> usize {
+    max_parallel.unwrap_or(Semaphore::MAX_PERMITS).clamp(1, Semaphore::MAX_PERMITS)
+}
+
+/// Abort handles for the `execute_pipeline` tasks still in flight, by run id.
+#[derive(Default)]
+pub struct ActiveRuns(Mutex<HashMap<String, AbortHandle>>);
+
+impl ActiveRuns {
+    fn insert(&self, pipeline_run_id: String, handle: AbortHandle) {
+        self.0.lock().unwrap().insert(pipeline_run_id, handle);
+    }
+
+    fn remove(&self, pipeline_run_id: &str) {
+        self.0.lock().unwrap().remove(pipeline_run_id);
+    }
+
+    /// Stops the run's task, which also aborts the jobs it has in flight.
+    fn abort(&self, pipeline_run_id: &str) {
+        if let Some(handle) = self.0.lock().unwrap().remove(pipeline_run_id) {
+            handle.abort();
+        }
+    }
+}
+
+/// Executors waiting for a worker to report a job's result through
+/// `PATCH /api/jobs/{id}` rather than in the response to `POST /job`.
+#[derive(Default)]
+pub struct PendingResults(Mutex<HashMap<String, oneshot::Sender<JobResult>>>);
+
+impl PendingResults {
+    fn register(&self, job_run_id: &str) -> oneshot::Receiver<JobResult> {
+        let (sender, receiver) = oneshot::channel();
+        self.0.lock().unwrap().insert(job_run_id.to_string(), sender);
+        receiver
+    }
+
+    fn forget(&self, job_run_id: &str) {
+        self.0.lock().unwrap().remove(job_run_id);
+    }
+
+    /// Hands a finished job's result to the executor waiting for it, which
+    /// then records it. Gives the result back if nothing is waiting.
+    pub fn deliver(&self, result: JobResult) -> Result<(), JobResult> {
+        let sender = self.0.lock().unwrap().remove(&result.id);
+        match sender {
+            Some(sender) => sender.send(result),
+            None => Err(result),
+        }
+    }
+
+    /// Tells the executor of a job whose lease expired to queue it again.
+    /// Returns false if nothing is waiting for the job.
+    pub fn requeue(&self, job_run_id: &str) -> bool {
+        self.deliver(JobResult {
+            id: job_run_id.to_string(),
+            status: JobStatus::Queued,
+            output: String::new(),
+            artifacts: vec![],
+            note: None,
+        }).is_ok()
+    }
+}
+
+// Timeout for pipelines that don't set their own
+fn default_pipeline_timeout() -> Duration {
+    duration::from_env("DEFAULT_PIPELINE_TIMEOUT", DEFAULT_PIPELINE_TIMEOUT)
+}
+
+const DEFAULT_PIPELINE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+// Target setting that lets builds of the target override its branch
+const ALLOW_BRANCH_OVERRIDE: &str = "allow_branch_override";
+
+pub async fn trigger_build(
+    build_request: web::Json<BuildRequest>,
+    job_slots: web::Data<JobSlots>,
+    active_runs: web::Data<ActiveRuns>,
+    pending_results: web::Data<PendingResults>,
+    workers: web::Data<WorkerPool>,
+) -> impl Responder {
+    start_build(Build {
+        repository: &build_request.repository,
+        branch: &build_request.branch,
+        commit: None,
+        target: None,
+        parameters: &Parameters::new(),
+    }, job_slots, active_runs, pending_results, workers).await
+}
+
+/// Starts a run for a target, from the head of its branch or a given commit,
+/// with the parameter values in the request.
+pub async fn trigger_target(
+    target_name: web::Path<String>,
+    request: web::Json<TriggerRequest>,
+    job_slots: web::Data<JobSlots>,
+    active_runs: web::Data<ActiveRuns>,
+    pending_results: web::Data<PendingResults>,
+    workers: web::Data<WorkerPool>,
+) -> impl Responder {
+    let target = match get_target(&target_name) {
+        Ok(Some(target)) => target,
+        Ok(None) => return HttpResponse::NotFound()
+            .body(format!("Target '{}' not found", target_name)),
+        Err(e) => return HttpResponse::InternalServerError()
+            .body(format!("Failed to get target: {}", e)),
+    };
+
+    let commit = request.commit.as_deref().filter(|commit| !commit.is_empty());
+    if let Some(commit) = commit {
+        if commit.len() < 4 || commit.len() > 40 || !commit.chars().all(|c| c.is_ascii_hexdigit()) {
+            return HttpResponse::BadRequest()
+                .body(format!("Invalid commit SHA '{}'", commit));
+        }
+    }
+
+    // A run gets the target's secrets, so building other branches, whose
+    // code nobody may have reviewed, has to be allowed explicitly
+    let branch = request.branch.as_deref().unwrap_or(&target.branch);
+    if !file::is_valid_branch(branch) {
+        return HttpResponse::BadRequest()
+            .body(format!("Invalid branch name '{}'", branch));
+    }
+    let allow_override = target.settings.get(ALLOW_BRANCH_OVERRIDE)
+        .and_then(|value| value.as_bool())
+        .unwrap_or(false);
+    if branch != target.branch && !allow_override {
+        return HttpResponse::Forbidden()
+            .body(format!(
+                "Target '{}' only builds branch '{}', set '{}' in its settings to build others",
+                target.name, target.branch, ALLOW_BRANCH_OVERRIDE
+            ));
+    }
+
+    start_build(Build {
+        repository: &target.repository,
+        branch,
+        commit,
+        target: Some(&target.name),
+        parameters: &request.parameters,
+    }, job_slots, active_runs, pending_results, workers).await
+}
+
+/// What a new run builds.
+struct Build<'a> {
+    repository: &'a str,
+    branch: &'a str,
+    /// Commit to build instead of the head of the branch.
+    commit: Option<&'a str>,
+    /// Target whose secrets the run uses, instead of the one for its
+    /// repository and branch.
+    target: Option<&'a str>,
+    parameters: &'a Parameters,
+}
+
+// Fetches the pipeline configuration of the commit to build and starts a run
+// of it
+async fn start_build(
+    build: Build<'_>,
+    job_slots: web::Data<JobSlots>,
+    active_runs: web::Data<ActiveRuns>,
+    pending_results: web::Data<PendingResults>,
+    workers: web::Data<WorkerPool>,
+) -> HttpResponse {
     // Clone repository and get pipeline configuration
-    let (temp_dir, config_content) = match file::clone_repository(
-        &build_request.repository,
-        &build_request.branch
-    ).await {
+    let cloned = match build.commit {
+        Some(commit) => file::clone_commit(build.repository, build.branch, commit).await,
+        None => file::clone_repository(build.repository, build.branch).await,
+    };
+    let (temp_dir, config_content) = match cloned {
         Ok(result) => result,
         Err(e) => return HttpResponse::BadRequest()
             .body(format!("Failed to fetch pipeline configuration: {}", e)),
     };
 
-    // Parse pipeline configuration
-    let pipeline: Pipeline = match serde_yaml::from_str(&config_content) {
-        Ok(p) => p,
+    // Pin the run to the commit the configuration came from
+    let commit_sha = match file::head_commit(temp_dir.path()) {
+        Ok(sha) => sha,
+        Err(e) => return HttpResponse::InternalServerError()
+            .body(format!("Failed to resolve commit: {}", e)),
+    };
+
+    let (mut pipeline, dependencies) = match prepare_pipeline(&config_content) {
+        Ok(prepared) => prepared,
         Err(e) => return HttpResponse::BadRequest()
             .body(format!("Invalid pipeline configuration: {}", e)),
     };
 
-    // Calculate total number of jobs
-    let total_jobs = pipeline.stages.iter()
-        .map(|stage| stage.jobs.len() as i32)
-        .sum();
+    let parameters = match pipeline.apply_parameters(build.parameters) {
+        Ok(parameters) => parameters,
+        Err(e) => return HttpResponse::BadRequest()
+            .body(format!("Invalid parameters: {}", e)),
+    };
 
-    // Create pipeline run
-    let pipeline_run_id = match create_pipeline_run(
-        &pipeline.name,
-        &build_request.repository,
-        &build_request.branch,
-        total_jobs
-    ) {
+    let pipeline_run_id = match create_run(&pipeline, NewPipelineRun {
+        name: &pipeline.name,
+        repository: build.repository,
+        branch: build.branch,
+        commit_sha: Some(&commit_sha),
+        pipeline_config: &config_content,
+        parent_run_id: None,
+        attempt: 1,
+        total_jobs: pipeline.jobs().count() as i32,
+        target: build.target,
+        parameters: &parameters,
+    }) {
         Ok(id) => id,
         Err(e) => return HttpResponse::InternalServerError()
             .body(format!("Failed to create pipeline run: {}", e)),
     };
 
-    // Create job runs
-    let mut job_index = 0;
-    for stage in &pipeline.stages {
-        for job in &stage.jobs {
-            if let Err(e) = create_job_run(&pipeline_run_id, &job.name, job_index) {
-                eprintln!("Failed to create job run: {}", e);
-            }
-            job_index += 1;
+    // Start pipeline execution
+    let finished = vec![None; dependencies.len()];
+    spawn_run(RunPlan {
+        pipeline_run_id: pipeline_run_id.clone(),
+        pipeline,
+        dependencies,
+        finished,
+        leased: HashSet::new(),
+        tries: HashMap::new(),
+        commit_sha: Some(commit_sha.clone()),
+    }, job_slots, active_runs, pending_results, workers);
+
+    HttpResponse::Ok().json(json!({
+        "pipeline_run_id": pipeline_run_id,
+        "commit_sha": commit_sha,
+        "parameters": parameters,
+        "status": "started"
+    }))
+}
+
+/// Starts a new run from the same commit and pipeline configuration as an
+/// earlier one, linked to it through `parent_run_id`.
+pub async fn rerun_run(
+    run_id: web::Path<String>,
+    job_slots: web::Data<JobSlots>,
+    active_runs: web::Data<ActiveRuns>,
+    pending_results: web::Data<PendingResults>,
+    workers: web::Data<WorkerPool>,
+) -> impl Responder {
+    let source = match get_run_source(&run_id) {
+        Ok(Some(source)) => source,
+        Ok(None) => return HttpResponse::NotFound()
+            .body(format!("Pipeline run '{}' not found", run_id)),
+        Err(e) => return HttpResponse::InternalServerError()
+            .body(format!("Database error: {}", e)),
+    };
+
+    if matches!(source.status, PipelineStatus::Pending | PipelineStatus::Running) {
+        return HttpResponse::Conflict()
+            .body(format!("Pipeline run '{}' is still running", run_id));
+    }
+
+    let Some(config_content) = source.pipeline_config.as_deref() else {
+        return HttpResponse::BadRequest()
+            .body(format!("Pipeline run '{}' has no stored pipeline configuration", run_id));
+    };
+
+    let (mut pipeline, dependencies) = match prepare_pipeline(config_content) {
+        Ok(prepared) => prepared,
+        Err(e) => return HttpResponse::InternalServerError()
+            .body(format!("Stored pipeline configuration is invalid: {}", e)),
+    };
+    if let Err(e) = pipeline.apply_parameters(&source.parameters) {
+        return HttpResponse::InternalServerError()
+            .body(format!("Stored parameters are invalid: {}", e));
+    }
+
+    let attempt = source.attempt + 1;
+    let pipeline_run_id = match create_run(&pipeline, NewPipelineRun {
+        name: &pipeline.name,
+        repository: &source.repository,
+        branch: &source.branch,
+        commit_sha: source.commit_sha.as_deref(),
+        pipeline_config: config_content,
+        parent_run_id: Some(&run_id),
+        attempt,
+        total_jobs: pipeline.jobs().count() as i32,
+        target: source.target.as_deref(),
+        parameters: &source.parameters,
+    }) {
+        Ok(id) => id,
+        Err(e) => return HttpResponse::InternalServerError()
+            .body(format!("Failed to create pipeline run: {}", e)),
+    };
+
+    let finished = vec![None; dependencies.len()];
+    spawn_run(RunPlan {
+        pipeline_run_id: pipeline_run_id.clone(),
+        pipeline,
+        dependencies,
+        finished,
+        leased: HashSet::new(),
+        tries: HashMap::new(),
+        commit_sha: source.commit_sha,
+    }, job_slots, active_runs, pending_results, workers);
+
+    HttpResponse::Ok().json(json!({
+        "pipeline_run_id": pipeline_run_id,
+        "parent_run_id": run_id.to_string(),
+        "attempt": attempt,
+        "status": "started"
+    }))
+}
+
+/// Runs a failed, skipped or cancelled job again inside its original run,
+/// together with the jobs downstream of it that did not succeed. Jobs that
+/// already succeeded keep their results.
+pub async fn retry_job(
+    job_id: web::Path<String>,
+    job_slots: web::Data<JobSlots>,
+    active_runs: web::Data<ActiveRuns>,
+    pending_results: web::Data<PendingResults>,
+    workers: web::Data<WorkerPool>,
+) -> impl Responder {
+    let job_run = match get_job_run(&job_id) {
+        Ok(Some(job_run)) => job_run,
+        Ok(None) => return HttpResponse::NotFound()
+            .body(format!("Job '{}' not found", job_id)),
+        Err(e) => return HttpResponse::InternalServerError()
+            .body(format!("Database error: {}", e)),
+    };
+
+    if !matches!(
+        job_run.status,
+        JobStatus::Failed | JobStatus::Error | JobStatus::TimedOut
+            | JobStatus::Skipped | JobStatus::Cancelled
+    ) {
+        return HttpResponse::Conflict()
+            .body(format!("Job '{}' is {} and cannot be retried", job_id, job_run.status));
+    }
+
+    let pipeline_run_id = job_run.pipeline_run_id;
+    let source = match get_run_source(&pipeline_run_id) {
+        Ok(Some(source)) => source,
+        Ok(None) => return HttpResponse::NotFound()
+            .body(format!("Pipeline run '{}' not found", pipeline_run_id)),
+        Err(e) => return HttpResponse::InternalServerError()
+            .body(format!("Database error: {}", e)),
+    };
+
+    if matches!(source.status, PipelineStatus::Pending | PipelineStatus::Running) {
+        return HttpResponse::Conflict()
+            .body(format!("Pipeline run '{}' is still running", pipeline_run_id));
+    }
+
+    let Some(config_content) = source.pipeline_config.as_deref() else {
+        return HttpResponse::BadRequest()
+            .body(format!("Pipeline run '{}' has no stored pipeline configuration", pipeline_run_id));
+    };
+
+    let (mut pipeline, dependencies) = match prepare_pipeline(config_content) {
+        Ok(prepared) => prepared,
+        Err(e) => return HttpResponse::InternalServerError()
+            .body(format!("Stored pipeline configuration is invalid: {}", e)),
+    };
+    if let Err(e) = pipeline.apply_parameters(&source.parameters) {
+        return HttpResponse::InternalServerError()
+            .body(format!("Stored parameters are invalid: {}", e));
+    }
+
+    let latest_jobs = match get_latest_job_runs(&pipeline_run_id) {
+        Ok(jobs) => jobs,
+        Err(e) => return HttpResponse::InternalServerError()
+            .body(format!("Database error: {}", e)),
+    };
+
+    if !latest_jobs.iter().any(|(id, ..)| *id == *job_id) {
+        return HttpResponse::Conflict()
+            .body(format!("Job '{}' has already been retried", job_id));
+    }
+
+    let mut finished: Vec<Option<JobStatus>> = vec![None; dependencies.len()];
+    let mut attempts = vec![0; dependencies.len()];
+    for (_, job_index, status, attempt) in latest_jobs {
+        if let Some(slot) = finished.get_mut(job_index as usize) {
+            *slot = Some(status);
+            attempts[job_index as usize] = attempt;
         }
     }
 
-    // Start pipeline execution
-    tokio::spawn(execute_pipeline(
-        pipeline_run_id.clone(),
+    // Walk downstream from the retried job, stopping at jobs that succeeded
+    let mut dependents = vec![Vec::new(); dependencies.len()];
+    for (index, needs) in dependencies.iter().enumerate() {
+        for &need in needs {
+            dependents[need].push(index);
+        }
+    }
+    let mut to_retry = Vec::new();
+    let mut queue = vec![job_run.job_index as usize];
+    while let Some(index) = queue.pop() {
+        if index >= finished.len() || to_retry.contains(&index)
+            || finished[index] == Some(JobStatus::Succeeded)
+        {
+            continue;
+        }
+        to_retry.push(index);
+        queue.extend(&dependents[index]);
+    }
+
+    let jobs: Vec<&Job> = pipeline.jobs().collect();
+    let retried: Vec<(String, i32, i32)> = to_retry.iter()
+        .map(|&index| (jobs[index].name.clone(), index as i32, attempts[index] + 1))
+        .collect();
+    // The checks above are repeated when the retry is recorded, in case
+    // another retry of the run got in between
+    match begin_job_retry(&pipeline_run_id, &retried) {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::Conflict()
+            .body(format!("Pipeline run '{}' is running again or job '{}' has already been retried", pipeline_run_id, job_id)),
+        Err(e) => return HttpResponse::InternalServerError()
+            .body(format!("Failed to retry jobs: {}", e)),
+    }
+
+    for &index in &to_retry {
+        finished[index] = None;
+    }
+    spawn_run(RunPlan {
+        pipeline_run_id: pipeline_run_id.clone(),
         pipeline,
-        build_request.0,
-    ));
+        dependencies,
+        finished,
+        leased: HashSet::new(),
+        tries: HashMap::new(),
+        commit_sha: source.commit_sha,
+    }, job_slots, active_runs, pending_results, workers);
 
     HttpResponse::Ok().json(json!({
         "pipeline_run_id": pipeline_run_id,
+        "retried_jobs": retried.iter().map(|(name, ..)| name).collect::<Vec<_>>(),
         "status": "started"
     }))
 }
 
+// Parses a pipeline configuration, expands matrix jobs and resolves the
+// dependencies between jobs
+fn prepare_pipeline(config_content: &str) -> Result<(Pipeline, Vec<Vec<usize>>), String> {
+    let mut pipeline: Pipeline = serde_yaml::from_str(config_content)
+        .map_err(|e| e.to_string())?;
+    pipeline.expand_matrices()?;
+    let dependencies = pipeline.dependencies()?;
+    Ok((pipeline, dependencies))
+}
+
+// Creates the pipeline run row and a pending row for each of its jobs
+fn create_run(pipeline: &Pipeline, run: NewPipelineRun) -> rusqlite::Result<String> {
+    let pipeline_run_id = create_pipeline_run(&run)?;
+
+    for (job_index, job) in pipeline.jobs().enumerate() {
+        create_job_run(&pipeline_run_id, &job.name, job_index as i32, 1)?;
+    }
+
+    Ok(pipeline_run_id)
+}
+
+/// A pipeline run ready to be handed to `execute_pipeline`.
+struct RunPlan {
+    pipeline_run_id: String,
+    pipeline: Pipeline,
+    dependencies: Vec<Vec<usize>>,
+    /// Outcome of jobs that should not run again; `None` for jobs to run.
+    finished: Vec<Option<JobStatus>>,
+    /// Jobs a pull worker still holds a lease on, whose result is awaited
+    /// instead of dispatching them again.
+    leased: HashSet<usize>,
+    /// Tries jobs have used up before this plan, which count against their
+    /// retry policy. Jobs not listed start afresh.
+    tries: HashMap<usize, u32>,
+    commit_sha: Option<String>,
+}
+
+fn spawn_run(
+    plan: RunPlan,
+    job_slots: web::Data<JobSlots>,
+    active_runs: web::Data<ActiveRuns>,
+    pending_results: web::Data<PendingResults>,
+    workers: web::Data<WorkerPool>,
+) {
+    let pipeline_run_id = plan.pipeline_run_id.clone();
+    let handle = tokio::spawn(execute_pipeline(
+        plan,
+        job_slots,
+        active_runs.clone(),
+        pending_results,
+        workers,
+    ));
+    active_runs.insert(pipeline_run_id, handle.abort_handle());
+}
+
+/// Picks up the runs that were in progress when the master stopped. Runs
+/// that can't be resumed are marked interrupted.
+pub async fn resume_runs(
+    job_slots: web::Data<JobSlots>,
+    active_runs: web::Data<ActiveRuns>,
+    pending_results: web::Data<PendingResults>,
+    workers: web::Data<WorkerPool>,
+) {
+    let run_ids = match get_unfinished_pipeline_runs() {
+        Ok(run_ids) => run_ids,
+        Err(e) => {
+            eprintln!("Failed to load unfinished pipeline runs: {}", e);
+            return;
+        }
+    };
+
+    let client = workers.client().clone();
+    for pipeline_run_id in run_ids {
+        match resume_plan(&client, &pipeline_run_id).await {
+            Ok(plan) => {
+                println!("Resuming pipeline run {}", pipeline_run_id);
+                spawn_run(
+                    plan,
+                    job_slots.clone(),
+                    active_runs.clone(),
+                    pending_results.clone(),
+                    workers.clone(),
+                );
+            }
+            Err(e) => {
+                eprintln!("Cannot resume pipeline run {}: {}", pipeline_run_id, e);
+                match interrupt_pipeline_run(&pipeline_run_id) {
+                    Ok(running_jobs) => {
+                        for job_id in running_jobs {
+                            tokio::spawn(cancel_job_on_worker(client.clone(), job_id));
+                        }
+                    }
+                    Err(e) => eprintln!("Failed to interrupt pipeline run {}: {}", pipeline_run_id, e),
+                }
+            }
+        }
+    }
+}
+
+// Rebuilds the plan of an unfinished run from the database. Jobs a pull
+// worker still holds a lease on keep running there. A job that was pushed
+// to a worker can't be followed any more, since its request or callback
+// registration was lost, so it is recorded as interrupted and runs again
+// as a new attempt.
+async fn resume_plan(client: &reqwest::Client, pipeline_run_id: &str) -> Result<RunPlan, String> {
+    let db_error = |e: rusqlite::Error| format!("Database error: {}", e);
+
+    let source = get_run_source(pipeline_run_id).map_err(db_error)?
+        .ok_or("the run no longer exists")?;
+    let config_content = source.pipeline_config.as_deref()
+        .ok_or("it has no stored pipeline configuration")?;
+    let (mut pipeline, dependencies) = prepare_pipeline(config_content)
+        .map_err(|e| format!("its pipeline configuration is invalid: {}", e))?;
+    pipeline.apply_parameters(&source.parameters)
+        .map_err(|e| format!("its parameters are invalid: {}", e))?;
+    let jobs: Vec<&Job> = pipeline.jobs().collect();
+
+    let mut finished = vec![None; dependencies.len()];
+    let mut leased = HashSet::new();
+    for (job_run_id, job_index, status, attempt) in get_latest_job_runs(pipeline_run_id).map_err(db_error)? {
+        let index = job_index as usize;
+        let Some(job) = jobs.get(index) else {
+            return Err(format!("job {} is not in its pipeline", job_index));
+        };
+
+        match status {
+            JobStatus::Pending | JobStatus::Queued => {}
+            JobStatus::Running => match get_job_worker(&job_run_id).map_err(db_error)? {
+                (Some(_), None) => {
+                    leased.insert(index);
+                }
+                _ => {
+                    // Open the next attempt first so the run isn't finished early
+                    create_job_run(pipeline_run_id, &job.name, job_index, attempt + 1)
+                        .map_err(db_error)?;
+                    append_job_note(&job_run_id, "Interrupted by a restart of the master")
+                        .map_err(db_error)?;
+                    update_job_status(&job_run_id, JobStatus::Error, None).map_err(db_error)?;
+                    tokio::spawn(cancel_job_on_worker(client.clone(), job_run_id.clone()));
+                }
+            },
+            status => finished[index] = Some(status),
+        }
+    }
+
+    // Settles runs whose jobs had all finished before the restart
+    update_pipeline_progress(pipeline_run_id).map_err(db_error)?;
+
+    // Every row but the one the next try goes in was a try already
+    let tries = count_job_runs(pipeline_run_id).map_err(db_error)?
+        .into_iter()
+        .map(|(job_index, count)| (job_index as usize, count.saturating_sub(1)))
+        .collect();
+
+    Ok(RunPlan {
+        pipeline_run_id: pipeline_run_id.to_string(),
+        pipeline,
+        dependencies,
+        finished,
+        leased,
+        tries,
+        commit_sha: source.commit_sha,
+    })
+}
+
 pub async fn get_status(pipeline_name: web::Path<String>) -> impl Responder {
     match get_pipeline_status(&pipeline_name) {
         Ok(Some((pipeline_run, jobs))) => HttpResponse::Ok().json(json!({
@@ -84,66 +692,496 @@ pub async fn get_status(pipeline_name: web::Path<String>) -> impl Responder {
     }
 }
 
-async fn execute_pipeline(
+pub async fn cancel_run(
+    run_id: web::Path<String>,
+    active_runs: web::Data<ActiveRuns>,
+    workers: web::Data<WorkerPool>,
+) -> impl Responder {
+    match get_pipeline_run_status(&run_id) {
+        Ok(Some(PipelineStatus::Pending | PipelineStatus::Running)) => {}
+        Ok(Some(status)) => return HttpResponse::Conflict()
+            .body(format!("Pipeline run '{}' has already finished ({})", run_id, status)),
+        Ok(None) => return HttpResponse::NotFound()
+            .body(format!("Pipeline run '{}' not found", run_id)),
+        Err(e) => return HttpResponse::InternalServerError()
+            .body(format!("Database error: {}", e)),
+    }
+
+    // Stop scheduling before touching the database so no new job gets dispatched
+    active_runs.abort(&run_id);
+
+    let running_jobs = match cancel_pipeline_run(&run_id) {
+        Ok(jobs) => jobs,
+        Err(e) => return HttpResponse::InternalServerError()
+            .body(format!("Failed to cancel pipeline run: {}", e)),
+    };
+
+    // The run is already cancelled in the database; workers are told in the
+    // background so a slow or unreachable worker doesn't hold up the response
+    for job_id in &running_jobs {
+        tokio::spawn(cancel_job_on_worker(workers.client().clone(), job_id.clone()));
+    }
+
+    HttpResponse::Ok().json(json!({
+        "pipeline_run_id": run_id.to_string(),
+        "status": PipelineStatus::Cancelled,
+        "cancelled_jobs": running_jobs
+    }))
+}
+
+// Pin a run so that pruning keeps it
+pub async fn pin_run(run_id: web::Path<String>) -> impl Responder {
+    set_pinned(&run_id, true)
+}
+
+pub async fn unpin_run(run_id: web::Path<String>) -> impl Responder {
+    set_pinned(&run_id, false)
+}
+
+fn set_pinned(run_id: &str, pinned: bool) -> HttpResponse {
+    match set_pipeline_run_pinned(run_id, pinned) {
+        Ok(true) => HttpResponse::Ok().json(json!({
+            "pipeline_run_id": run_id,
+            "pinned": pinned
+        })),
+        Ok(false) => HttpResponse::NotFound()
+            .body(format!("Pipeline run '{}' not found", run_id)),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Failed to pin pipeline run: {}", e)),
+    }
+}
+
+// Asks the worker running a job to abort it. Failures are only logged since
+// the job is already marked cancelled in the database.
+async fn cancel_job_on_worker(client: reqwest::Client, job_id: String) {
+    let worker_url = match get_job_worker(&job_id) {
+        Ok((None, _)) => worker_url(),
+        Ok((Some(_), Some(url))) => url,
+        // A pull worker finds out when it next renews its lease
+        Ok((Some(_), None)) => return,
+        Err(e) => {
+            eprintln!("Failed to look up the worker of job {}: {}", job_id, e);
+            return;
+        }
+    };
+
+    let result = client.post(format!("{}/job/{}/cancel", worker_url, job_id))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+
+    if let Err(e) = result {
+        eprintln!("Failed to cancel job {} on worker: {}", job_id, e);
+    }
+}
+
+/// What every job task of a run shares.
+struct RunContext {
     pipeline_run_id: String,
-    pipeline: Pipeline,
-    build_request: BuildRequest,
+    commit_sha: Option<String>,
+    /// Target whose secrets the run's jobs can use.
+    target: Option<String>,
+    client: reqwest::Client,
+    /// Upper bound on the whole run, and so on how long a worker may take
+    /// to answer a job's request.
+    timeout: Duration,
+    slots: Slots,
+    pending_results: web::Data<PendingResults>,
+    workers: web::Data<WorkerPool>,
+}
+
+// The target a run was made from: the one it was triggered through, or
+// else the one for its repository and branch
+fn run_target(pipeline_run_id: &str) -> Option<String> {
+    let source = match get_run_source(pipeline_run_id) {
+        Ok(source) => source?,
+        Err(e) => {
+            eprintln!("Failed to get run {}: {}", pipeline_run_id, e);
+            return None;
+        }
+    };
+    if source.target.is_some() {
+        return source.target;
+    }
+
+    match get_target_for_branch(&source.repository, &source.branch) {
+        Ok(target) => target.map(|t| t.name),
+        Err(e) => {
+            eprintln!("Failed to find target of run {}: {}", pipeline_run_id, e);
+            None
+        }
+    }
+}
+
+async fn execute_pipeline(
+    plan: RunPlan,
+    job_slots: web::Data<JobSlots>,
+    active_runs: web::Data<ActiveRuns>,
+    pending_results: web::Data<PendingResults>,
+    workers: web::Data<WorkerPool>,
 ) {
-    let worker_url = std::env::var("WORKER_URL")
-        .unwrap_or_else(|_| "http://localhost:8080".to_string());
-    let client = reqwest::Client::new();
-
-    for stage in pipeline.stages {
-        for job in stage.jobs {
-            let job_result = execute_job(&client, &worker_url, &job).await;
-            
-            // Update job status in database
-            if let Err(e) = update_job_status(
-                &job_result.id,
-                job_result.status.clone(),
-                Some(&job_result.output)
-            ) {
-                eprintln!("Failed to update job status: {}", e);
+    let RunPlan {
+        pipeline_run_id,
+        pipeline,
+        dependencies,
+        finished,
+        leased,
+        tries,
+        commit_sha,
+    } = plan;
+
+    // The rows each job's next try is recorded in
+    let mut job_runs: Vec<Option<(String, i32)>> = vec![None; dependencies.len()];
+    match get_latest_job_runs(&pipeline_run_id) {
+        Ok(rows) => {
+            for (id, job_index, _, attempt) in rows {
+                if let Some(slot) = job_runs.get_mut(job_index as usize) {
+                    *slot = Some((id, attempt));
+                }
             }
+        }
+        Err(e) => {
+            eprintln!("Failed to load job runs for {}: {}", pipeline_run_id, e);
+            active_runs.remove(&pipeline_run_id);
+            return;
+        }
+    }
+
+    let timeout = pipeline.timeout.unwrap_or_else(default_pipeline_timeout);
+    let context = Arc::new(RunContext {
+        target: run_target(&pipeline_run_id),
+        pipeline_run_id: pipeline_run_id.clone(),
+        commit_sha,
+        client: workers.client().clone(),
+        timeout,
+        slots: Slots::new(pipeline.max_parallel, &job_slots),
+        pending_results,
+        workers,
+    });
+
+    let jobs: Vec<Job> = pipeline.jobs().cloned().collect();
+    let schedule = schedule_jobs(&context, jobs, &dependencies, finished, leased, tries, job_runs);
 
-            // Break pipeline execution if job failed
-            if job_result.status == JobStatus::Failed {
-                break;
+    // Dropping the schedule on timeout aborts every job task still in flight
+    if tokio::time::timeout(timeout, schedule).await.is_err() {
+        match time_out_pipeline_run(&pipeline_run_id) {
+            Ok(running_jobs) => {
+                for job_id in running_jobs {
+                    tokio::spawn(cancel_job_on_worker(context.client.clone(), job_id));
+                }
             }
+            Err(e) => eprintln!("Failed to time out pipeline run {}: {}", pipeline_run_id, e),
         }
     }
+
+    active_runs.remove(&pipeline_run_id);
 }
 
-async fn execute_job(
-    client: &reqwest::Client,
-    worker_url: &str,
-    job: &Job,
-) -> JobResult {
-    let job_id = Uuid::new_v4().to_string();
+// Starts each job once its dependencies allow it and waits for all of them
+async fn schedule_jobs(
+    context: &Arc<RunContext>,
+    jobs: Vec<Job>,
+    dependencies: &[Vec<usize>],
+    mut finished: Vec<Option<JobStatus>>,
+    leased: HashSet<usize>,
+    tries: HashMap<usize, u32>,
+    mut job_runs: Vec<Option<(String, i32)>>,
+) {
+    let mut started: Vec<bool> = finished.iter().map(Option::is_some).collect();
+    let mut running = JoinSet::new();
 
-    match client.post(&format!("{}/job", worker_url))
-        .json(&job)
-        .send()
-        .await {
-        Ok(response) => {
-            match response.json::<JobResult>().await {
-                Ok(mut result) => {
-                    result.id = job_id;
-                    result
-                },
+    loop {
+        // Start every job whose dependencies have all succeeded. A job whose
+        // dependency failed or was skipped is skipped too, which can in turn
+        // settle further jobs, so keep scanning until nothing changes.
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (index, needs) in dependencies.iter().enumerate() {
+                if started[index] {
+                    continue;
+                }
+
+                let blocked = needs.iter().any(|&need| matches!(
+                    &finished[need],
+                    Some(status) if *status != JobStatus::Succeeded
+                ));
+                let ready = needs.iter()
+                    .all(|&need| finished[need] == Some(JobStatus::Succeeded));
+
+                if blocked {
+                    started[index] = true;
+                    finished[index] = Some(JobStatus::Skipped);
+                    changed = true;
+                    if let Some((job_run_id, _)) = job_runs[index].take() {
+                        if let Err(e) = update_job_status(&job_run_id, JobStatus::Skipped, None) {
+                            eprintln!("Failed to update job status: {}", e);
+                        }
+                    }
+                } else if ready {
+                    started[index] = true;
+                    let Some((job_run_id, attempt)) = job_runs[index].take() else {
+                        eprintln!("No job run recorded for job '{}'", jobs[index].name);
+                        finished[index] = Some(JobStatus::Error);
+                        changed = true;
+                        continue;
+                    };
+
+                    let context = context.clone();
+                    let job = jobs[index].clone();
+                    let leased = leased.contains(&index);
+                    let retry = tries.get(&index).copied().unwrap_or(0);
+                    running.spawn(async move {
+                        (index, run_job(&context, index, job, job_run_id, attempt, retry, leased).await)
+                    });
+                }
+            }
+        }
+
+        match running.join_next().await {
+            Some(Ok((index, status))) => finished[index] = Some(status),
+            Some(Err(e)) => eprintln!("Job task panicked: {}", e),
+            None => break,
+        }
+    }
+}
+
+// Runs one job to completion, retrying it as far as its retry policy
+// allows. Every try is recorded in its own `job_runs` row; `retry` is how
+// many tries the job had before. A job that is `leased` already is waiting
+// for its pull worker's result.
+async fn run_job(
+    context: &RunContext,
+    index: usize,
+    job: Job,
+    mut job_run_id: String,
+    mut attempt: i32,
+    mut retry: u32,
+    mut leased: bool,
+) -> JobStatus {
+    loop {
+        retry += 1;
+        let prepared = secrets::resolve(&job, context.target.as_deref())
+            .and_then(|(mut resolved, secret_values)| {
+                let artifacts = resolve_artifacts(&context.pipeline_run_id, &mut resolved)?;
+                Ok((resolved, secret_values, artifacts))
+            });
+        let (resolved, secret_values, artifacts) = match prepared {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                if let Err(e) = append_job_note(&job_run_id, &e)
+                    .and_then(|_| update_job_status(&job_run_id, JobStatus::Error, None))
+                {
+                    eprintln!("Failed to update job status: {}", e);
+                }
+                return JobStatus::Error;
+            }
+        };
+        // Held until the job's final status and output are stored
+        let _masking = secrets::Masking::new(&job_run_id, &secret_values);
+        let request = JobRequest {
+            id: job_run_id.clone(),
+            job: resolved,
+            commit: context.commit_sha.clone(),
+            artifacts,
+        };
+
+        let mut job_result = loop {
+            // Listen for a callback before the job can reach a worker so
+            // it can't be missed
+            let callback = context.pending_results.register(&job_run_id);
+
+            // The job's slots are held until its result is in
+            let assignment = if std::mem::take(&mut leased) {
+                Ok((Assignment::Leased, context.slots.take().await))
+            } else {
+                context.workers.assign(&request, &context.slots).await
+            };
+
+            let job_result = match assignment {
+                Ok((worker, _permits)) => {
+                    let execution = execute_job(context, &worker, &request, callback);
+
+                    match request.job.timeout {
+                        None => execution.await,
+                        Some(timeout) => match tokio::time::timeout(timeout, execution).await {
+                            Ok(job_result) => job_result,
+                            Err(_) => {
+                                context.pending_results.forget(&job_run_id);
+                                JobResult {
+                                    id: job_run_id.clone(),
+                                    status: JobStatus::TimedOut,
+                                    output: String::new(),
+                                    artifacts: vec![],
+                                    note: Some(format!("Job timed out after {}", duration::format(&timeout))),
+                                }
+                            }
+                        },
+                    }
+                }
                 Err(e) => JobResult {
-                    id: job_id,
-                    status: JobStatus::Failed,
-                    output: format!("Failed to parse worker response: {}", e),
+                    id: job_run_id.clone(),
+                    status: JobStatus::Error,
+                    output: String::new(),
                     artifacts: vec![],
+                    note: Some(format!("Failed to assign the job to a worker: {}", e)),
                 },
+            };
+            context.pending_results.forget(&job_run_id);
+
+            // The lease on the job expired, so offer it to workers again
+            if job_result.status != JobStatus::Queued {
+                break job_result;
+            }
+        };
+
+        for artifact in &job_result.artifacts {
+            if let Err(e) = save_reported_artifact(&job_run_id, artifact) {
+                eprintln!("{}", e);
             }
         }
-        Err(e) => JobResult {
-            id: job_id,
-            status: JobStatus::Failed,
-            output: format!("Failed to communicate with worker: {}", e),
-            artifacts: vec![],
-        },
+
+        // A job only succeeds once the outputs later jobs rely on are here.
+        // The reason goes after the job's log.
+        if job_result.status == JobStatus::Succeeded {
+            match missing_outputs(&job_run_id, &job) {
+                Ok(missing) if !missing.is_empty() => {
+                    job_result.status = JobStatus::Failed;
+                    job_result.note = Some(format!(
+                        "Job did not upload its declared outputs: {}",
+                        missing.join(", ")
+                    ));
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("Failed to check outputs of job {}: {}", job_run_id, e),
+            }
+        }
+
+        let delay = job.retry.as_ref()
+            .and_then(|policy| policy.delay_before(retry, &job_result.status));
+
+        // Open the next attempt before closing this one, so the run is
+        // never seen as failed while a retry is still to come
+        let next_job_run_id = delay.and_then(|_| match create_job_run(
+            &context.pipeline_run_id,
+            &job.name,
+            index as i32,
+            attempt + 1,
+        ) {
+            Ok(id) => Some(id),
+            Err(e) => {
+                eprintln!("Failed to create retry for job '{}': {}", job.name, e);
+                None
+            }
+        });
+
+        // The log is written first, so it is complete once the job is seen
+        // finished. An empty output keeps whatever the worker already
+        // reported or streamed while the job was running.
+        let held_back = secrets::flush(&job_run_id);
+        if !held_back.is_empty() {
+            if let Err(e) = logs::append(&job_run_id, &held_back) {
+                eprintln!("Failed to append to log of job {}: {}", job_run_id, e);
+            }
+        }
+        if !job_result.output.is_empty() {
+            if let Err(e) = replace_job_log(&job_run_id, &job_result.output) {
+                eprintln!("Failed to store log of job {}: {}", job_run_id, e);
+            }
+        }
+        if let Some(note) = &job_result.note {
+            if let Err(e) = append_job_note(&job_run_id, note) {
+                eprintln!("Failed to append to log of job {}: {}", job_run_id, e);
+            }
+        }
+
+        // Update job status in database
+        if let Err(e) = update_job_status(&job_run_id, job_result.status.clone(), None) {
+            eprintln!("Failed to update job status: {}", e);
+        }
+        context.workers.release();
+
+        // The job's slots are free again by now, so a slow or unreachable
+        // worker doesn't hold up the run
+        if job_result.status == JobStatus::TimedOut {
+            tokio::spawn(cancel_job_on_worker(context.client.clone(), job_run_id.clone()));
+        }
+
+        match (delay, next_job_run_id) {
+            (Some(delay), Some(next_job_run_id)) => {
+                tokio::time::sleep(delay).await;
+                job_run_id = next_job_run_id;
+                attempt += 1;
+            }
+            _ => return job_result.status,
+        }
     }
-}
\ No newline at end of file
+}
+
+// Sends a job to the worker. A worker either answers with the job's result
+// once it has finished, or accepts the job (202 Accepted, or a result that is
+// still pending or running) and reports the outcome later through
+// `PATCH /api/jobs/{id}`, which arrives on `callback`. Jobs leased by a pull
+// worker only ever report through `callback`.
+async fn execute_job(
+    context: &RunContext,
+    worker: &Assignment,
+    job: &JobRequest,
+    callback: oneshot::Receiver<JobResult>,
+) -> JobResult {
+    let failure = |status: JobStatus, note: String| JobResult {
+        id: job.id.clone(),
+        status,
+        output: String::new(),
+        artifacts: vec![],
+        note: Some(note),
+    };
+
+    let mut callback = callback;
+    let reported = match worker {
+        Assignment::Leased => callback.await,
+        Assignment::Push(worker_url) => {
+            let dispatch = async {
+                // The worker may hold the request open until the job is done
+                let response = context.client.post(format!("{}/job", worker_url))
+                    .timeout(context.timeout)
+                    .json(&job)
+                    .send()
+                    .await
+                    .map_err(|e| format!("Failed to communicate with worker: {}", e))?;
+
+                if response.status() == reqwest::StatusCode::ACCEPTED {
+                    return Ok(None);
+                }
+
+                match response.json::<JobResult>().await {
+                    Ok(result) if result.status.is_finished() => Ok(Some(result)),
+                    Ok(_) => Ok(None),
+                    Err(e) => Err(format!("Failed to parse worker response: {}", e)),
+                }
+            };
+
+            // A callback can settle the job before the worker answers, e.g.
+            // when the worker goes offline while holding the request open
+            tokio::select! {
+                reported = &mut callback => reported,
+                dispatched = dispatch => match dispatched {
+                    Ok(Some(mut result)) => {
+                        result.id = job.id.clone();
+                        return result;
+                    }
+                    Ok(None) => callback.await,
+                    Err(note) => return failure(JobStatus::Error, note),
+                },
+            }
+        }
+    };
+
+    match reported {
+        Ok(result) => result,
+        Err(_) => failure(
+            JobStatus::Error,
+            "Stopped waiting for the worker to report the job's result".to_string(),
+        ),
+    }
+}
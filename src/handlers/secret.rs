@@ -0,0 +1,101 @@
+use actix_web::{web, HttpResponse, Responder};
+
+use crate::models::secret::PutSecretRequest;
+use crate::db::operations::{
+    save_target_secret,
+    get_target_secret_info,
+    get_target_secrets,
+    delete_target_secret,
+    get_target,
+};
+use crate::utils::secrets;
+
+// The response to send if the target can't be found
+fn check_target(target_name: &str) -> Option<HttpResponse> {
+    match get_target(target_name) {
+        Ok(Some(_)) => None,
+        Ok(None) => Some(HttpResponse::NotFound()
+            .body(format!("Target '{}' not found", target_name))),
+        Err(e) => Some(HttpResponse::InternalServerError()
+            .body(format!("Failed to get target: {}", e))),
+    }
+}
+
+// Secret names have to work in `${{ secrets.NAME }}` and `SECRET_<NAME>`
+fn is_valid_name(name: &str) -> bool {
+    name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Set a target's secret. The value is encrypted before it is stored and is
+// never returned.
+pub async fn put_secret(
+    path: web::Path<(String, String)>,
+    request: web::Json<PutSecretRequest>,
+) -> impl Responder {
+    let (target_name, name) = path.into_inner();
+    if let Some(response) = check_target(&target_name) {
+        return response;
+    }
+    if !is_valid_name(&name) {
+        return HttpResponse::BadRequest().body(format!(
+            "Invalid secret name '{}': use letters, digits and underscores",
+            name
+        ));
+    }
+
+    let (nonce, value) = match secrets::encrypt(&target_name, &name, &request.value) {
+        Ok(encrypted) => encrypted,
+        Err(e) => return HttpResponse::ServiceUnavailable().body(e),
+    };
+
+    match save_target_secret(&target_name, &name, &nonce, &value) {
+        Ok(info) => HttpResponse::Ok().json(info),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Failed to save secret: {}", e)),
+    }
+}
+
+// List a target's secrets, without their values
+pub async fn list_secrets(target_name: web::Path<String>) -> impl Responder {
+    if let Some(response) = check_target(&target_name) {
+        return response;
+    }
+
+    match get_target_secrets(&target_name) {
+        Ok(secrets) => HttpResponse::Ok().json(secrets),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Failed to get secrets: {}", e)),
+    }
+}
+
+// Get a secret's metadata. Its value is only ever sent to workers.
+pub async fn get_secret(path: web::Path<(String, String)>) -> impl Responder {
+    let (target_name, name) = path.into_inner();
+    if let Some(response) = check_target(&target_name) {
+        return response;
+    }
+
+    match get_target_secret_info(&target_name, &name) {
+        Ok(Some(info)) => HttpResponse::Ok().json(info),
+        Ok(None) => HttpResponse::NotFound()
+            .body(format!("Secret '{}' not found for target '{}'", name, target_name)),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Failed to get secret: {}", e)),
+    }
+}
+
+pub async fn delete_secret(path: web::Path<(String, String)>) -> impl Responder {
+    let (target_name, name) = path.into_inner();
+    if let Some(response) = check_target(&target_name) {
+        return response;
+    }
+
+    match delete_target_secret(&target_name, &name) {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound()
+            .body(format!("Secret '{}' not found for target '{}'", name, target_name)),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Failed to delete secret: {}", e)),
+    }
+}
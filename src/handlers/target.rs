@@ -1,111 +1,331 @@
 use actix_web::{web, HttpResponse, Responder};
-use std::sync::Mutex;
-use serde_json;
+use actix_web::http::header::HeaderName;
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::{self, json};
 
-use crate::models::target::{Target, Targets, AddTargetRequest};
+use crate::models::target::{Target, Targets, AddTargetRequest, UpdateTargetRequest};
+use crate::db::operations::{
+    create_target,
+    get_target,
+    get_targets,
+    find_pipeline_config_commits,
+    get_pipeline_config,
+    get_pipeline_config_versions,
+    import_targets,
+    save_pipeline_config,
+    update_target as save_target,
+    delete_target as remove_target,
+    TargetDeletion,
+};
+use crate::storage::retention;
 use crate::utils::file;
 
-pub async fn add_target(
-    target_request: web::Json<AddTargetRequest>,
-    data: web::Data<Mutex<()>>,
-) -> impl Responder {
-    let _lock = data.lock().unwrap();
-    
-    // Load existing targets
-    let mut targets = match file::read_file("targets.json") {
-        Ok(content) => match serde_json::from_str::<Targets>(&content) {
-            Ok(t) => t,
-            Err(e) => return HttpResponse::InternalServerError()
-                .body(format!("Failed to parse targets file: {}", e)),
-        },
-        Err(e) => return HttpResponse::InternalServerError()
-            .body(format!("Failed to read targets file: {}", e)),
-    };
-    
+// Where earlier versions kept the list of targets
+const TARGETS_FILE: &str = "targets.json";
+
+// Where earlier versions kept the pipeline configuration of each target
+const CONFIG_DIR: &str = "targets";
+
+const COMMIT_HEADER: HeaderName = HeaderName::from_static("x-commit-sha");
+
+pub async fn add_target(target_request: web::Json<AddTargetRequest>) -> impl Responder {
     // Generate target name if not provided
     let target_name = target_request.name.clone()
         .unwrap_or_else(|| file::extract_repo_name(&target_request.repository));
-    
+
     // Check if target name already exists
-    if targets.targets.iter().any(|t| t.name == target_name) {
-        return HttpResponse::BadRequest()
-            .body(format!("Target name '{}' already exists", target_name));
+    match get_target(&target_name) {
+        Ok(None) => {}
+        Ok(Some(_)) => return HttpResponse::BadRequest()
+            .body(format!("Target name '{}' already exists", target_name)),
+        Err(e) => return HttpResponse::InternalServerError()
+            .body(format!("Failed to get target: {}", e)),
     }
-    
-    // Clone repository and get pipeline configuration
-    let (temp_dir, config_content) = match file::clone_repository(
-        &target_request.repository,
-        &target_request.branch
-    ).await {
+
+    let (commit_sha, config) = match fetch_pipeline_config(&target_request.repository, &target_request.branch).await {
         Ok(result) => result,
         Err(e) => return HttpResponse::BadRequest()
             .body(format!("Failed to fetch pipeline config: {}", e)),
     };
-    
-    // Save pipeline configuration to targets directory
-    let filename = file::repo_to_filename(&target_request.repository, &target_request.branch);
-    let path = format!("targets/{}", filename);
-    if let Err(e) = file::save_file(&path, &config_content) {
-        return HttpResponse::InternalServerError()
-            .body(format!("Failed to save pipeline config: {}", e));
-    }
-    
-    // Add new target
-    targets.targets.push(Target {
+
+    // Add new target. The name is checked again since another request may
+    // have taken it while the repository was cloned.
+    let target = Target {
         name: target_name,
         repository: target_request.repository.clone(),
         branch: target_request.branch.clone(),
-    });
-    
-    // Save updated targets
-    if let Err(e) = file::save_file(
-        "targets.json",
-        &serde_json::to_string_pretty(&targets).unwrap()
-    ) {
+        created_at: Utc::now(),
+        settings: target_request.settings.clone(),
+    };
+    match create_target(&target) {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::BadRequest()
+            .body(format!("Target name '{}' already exists", target.name)),
+        Err(e) => return HttpResponse::InternalServerError()
+            .body(format!("Failed to save target: {}", e)),
+    }
+
+    if let Err(e) = save_pipeline_config(&target.name, Some(&commit_sha), &config) {
         return HttpResponse::InternalServerError()
-            .body(format!("Failed to save targets: {}", e));
+            .body(format!("Failed to save pipeline config: {}", e));
+    }
+
+    match get_targets() {
+        Ok(targets) => HttpResponse::Ok().json(Targets { targets }),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Failed to get targets: {}", e)),
     }
-    
-    HttpResponse::Ok().json(targets)
 }
 
-pub async fn list_targets() -> impl Responder {
-    match file::read_file("targets.json") {
-        Ok(content) => match serde_json::from_str::<Targets>(&content) {
-            Ok(targets) => HttpResponse::Ok().json(targets),
-            Err(e) => HttpResponse::InternalServerError()
-                .body(format!("Failed to parse targets file: {}", e)),
-        },
+// Clones the repository and reads its pipeline configuration. Returns the
+// commit it was read from along with the configuration.
+async fn fetch_pipeline_config(repository: &str, branch: &str) -> Result<(String, String), String> {
+    let (temp_dir, config) = file::clone_repository(repository, branch).await
+        .map_err(|e| e.to_string())?;
+    let commit_sha = file::head_commit(temp_dir.path())
+        .map_err(|e| e.to_string())?;
+    Ok((commit_sha, config))
+}
+
+// Change a target's repository, branch or settings. Its pipeline
+// configuration is fetched again, so a failing clone changes nothing.
+pub async fn update_target(
+    target_name: web::Path<String>,
+    request: web::Json<UpdateTargetRequest>,
+) -> impl Responder {
+    let current = match get_target(&target_name) {
+        Ok(Some(target)) => target,
+        Ok(None) => return HttpResponse::NotFound()
+            .body(format!("Target '{}' not found", target_name)),
+        Err(e) => return HttpResponse::InternalServerError()
+            .body(format!("Failed to get target: {}", e)),
+    };
+
+    let request = request.into_inner();
+    let target = Target {
+        repository: request.repository.unwrap_or_else(|| current.repository.clone()),
+        branch: request.branch.unwrap_or_else(|| current.branch.clone()),
+        settings: request.settings.unwrap_or_else(|| current.settings.clone()),
+        ..current
+    };
+
+    let (commit_sha, config) = match fetch_pipeline_config(&target.repository, &target.branch).await {
+        Ok(result) => result,
+        Err(e) => return HttpResponse::BadRequest()
+            .body(format!("Failed to fetch pipeline config: {}", e)),
+    };
+
+    match save_target(&target) {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::NotFound()
+            .body(format!("Target '{}' not found", target_name)),
+        Err(e) => return HttpResponse::InternalServerError()
+            .body(format!("Failed to save target: {}", e)),
+    }
+
+    if let Err(e) = save_pipeline_config(&target.name, Some(&commit_sha), &config) {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to save pipeline config: {}", e));
+    }
+
+    HttpResponse::Ok().json(target)
+}
+
+// Fetch a target's pipeline configuration again from the head of its branch.
+// Earlier configurations are kept.
+pub async fn refresh_target(target_name: web::Path<String>) -> impl Responder {
+    let target = match get_target(&target_name) {
+        Ok(Some(target)) => target,
+        Ok(None) => return HttpResponse::NotFound()
+            .body(format!("Target '{}' not found", target_name)),
+        Err(e) => return HttpResponse::InternalServerError()
+            .body(format!("Failed to get target: {}", e)),
+    };
+
+    let (commit_sha, config) = match fetch_pipeline_config(&target.repository, &target.branch).await {
+        Ok(result) => result,
+        Err(e) => return HttpResponse::BadGateway()
+            .body(format!("Failed to fetch pipeline config: {}", e)),
+    };
+
+    match save_pipeline_config(&target.name, Some(&commit_sha), &config) {
+        Ok((version, updated)) => HttpResponse::Ok().json(json!({
+            "name": target.name,
+            "version": version,
+            "updated": updated
+        })),
         Err(e) => HttpResponse::InternalServerError()
-            .body(format!("Failed to read targets file: {}", e)),
+            .body(format!("Failed to save pipeline config: {}", e)),
     }
 }
 
-pub async fn get_target_pipeline(target_name: web::Path<String>) -> impl Responder {
-    let targets = match file::read_file("targets.json") {
-        Ok(content) => match serde_json::from_str::<Targets>(&content) {
-            Ok(t) => t,
-            Err(e) => return HttpResponse::InternalServerError()
-                .body(format!("Failed to parse targets file: {}", e)),
-        },
+#[derive(Deserialize)]
+pub struct DeleteTargetQuery {
+    /// Also delete the target's runs, with their logs and artifacts.
+    #[serde(default)]
+    pub purge: bool,
+}
+
+// Delete a target and its secrets, and with `?purge=true` its run history
+pub async fn delete_target(
+    target_name: web::Path<String>,
+    query: web::Query<DeleteTargetQuery>,
+) -> impl Responder {
+    let (runs, job_ids) = match remove_target(&target_name, query.purge) {
+        Ok(TargetDeletion::Deleted { runs, job_ids }) => (runs, job_ids),
+        Ok(TargetDeletion::NotFound) => return HttpResponse::NotFound()
+            .body(format!("Target '{}' not found", target_name)),
+        Ok(TargetDeletion::RunsInProgress) => return HttpResponse::Conflict()
+            .body(format!("Target '{}' has runs in progress", target_name)),
         Err(e) => return HttpResponse::InternalServerError()
-            .body(format!("Failed to read targets file: {}", e)),
+            .body(format!("Failed to delete target: {}", e)),
     };
-    
-    let target = match targets.targets.iter()
-        .find(|t| t.name == *target_name)
-    {
-        Some(t) => t,
-        None => return HttpResponse::NotFound()
+
+    // Artifact content is left for the next pruning pass, which deletes it
+    // once no artifact refers to it
+    match web::block(move || retention::delete_logs(&job_ids)).await {
+        Ok(Ok(_)) => HttpResponse::Ok().json(json!({
+            "name": target_name.as_str(),
+            "deleted_runs": runs
+        })),
+        Ok(Err(e)) => HttpResponse::InternalServerError()
+            .body(format!("Deleted target '{}', but failed to delete its logs: {}", target_name, e)),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Deleted target '{}', but failed to delete its logs: {}", target_name, e)),
+    }
+}
+
+pub async fn list_targets() -> impl Responder {
+    match get_targets() {
+        Ok(targets) => HttpResponse::Ok().json(Targets { targets }),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Failed to get targets: {}", e)),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PipelineQuery {
+    /// Commit SHA, or a prefix of one, of the configuration to return
+    /// instead of the current one.
+    pub version: Option<String>,
+}
+
+pub async fn get_target_pipeline(
+    target_name: web::Path<String>,
+    query: web::Query<PipelineQuery>,
+) -> impl Responder {
+    match get_target(&target_name) {
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::NotFound()
             .body(format!("Target '{}' not found", target_name)),
+        Err(e) => return HttpResponse::InternalServerError()
+            .body(format!("Failed to get target: {}", e)),
+    }
+
+    let version = query.version.as_deref().filter(|version| !version.is_empty());
+    let commit = match version {
+        Some(version) => {
+            if version.len() < 4 || version.len() > 40 || !version.chars().all(|c| c.is_ascii_hexdigit()) {
+                return HttpResponse::BadRequest()
+                    .body(format!("Invalid version '{}': expected a commit SHA of 4 to 40 hex characters", version));
+            }
+            // A prefix must not pick one of several commits at random
+            match find_pipeline_config_commits(&target_name, version, 2) {
+                Ok(commits) if commits.len() > 1 => return HttpResponse::Conflict()
+                    .body(format!("Version '{}' matches more than one commit; give more of the SHA", version)),
+                Ok(commits) => match commits.into_iter().next() {
+                    Some(commit) => Some(commit),
+                    None => return HttpResponse::NotFound()
+                        .body(format!("Target '{}' has no pipeline config from commit '{}'", target_name, version)),
+                },
+                Err(e) => return HttpResponse::InternalServerError()
+                    .body(format!("Failed to get pipeline config: {}", e)),
+            }
+        }
+        None => None,
     };
-    
-    let filename = file::repo_to_filename(&target.repository, &target.branch);
-    let path = format!("targets/{}", filename);
-    
-    match file::read_file(&path) {
-        Ok(config) => HttpResponse::Ok().body(config),
+
+    match get_pipeline_config(&target_name, commit.as_deref()) {
+        Ok(Some((version, config))) => {
+            let mut response = HttpResponse::Ok();
+            if let Some(commit_sha) = version.commit_sha {
+                response.insert_header((COMMIT_HEADER, commit_sha));
+            }
+            response.body(config)
+        }
+        Ok(None) => match version {
+            Some(version) => HttpResponse::NotFound()
+                .body(format!("Target '{}' has no pipeline config from commit '{}'", target_name, version)),
+            None => HttpResponse::NotFound()
+                .body(format!("Target '{}' has no pipeline config", target_name)),
+        },
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Failed to get pipeline config: {}", e)),
+    }
+}
+
+pub async fn list_pipeline_versions(target_name: web::Path<String>) -> impl Responder {
+    match get_target(&target_name) {
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::NotFound()
+            .body(format!("Target '{}' not found", target_name)),
+        Err(e) => return HttpResponse::InternalServerError()
+            .body(format!("Failed to get target: {}", e)),
+    }
+
+    match get_pipeline_config_versions(&target_name) {
+        Ok(versions) => HttpResponse::Ok().json(json!({ "versions": versions })),
         Err(e) => HttpResponse::InternalServerError()
-            .body(format!("Failed to read pipeline config: {}", e)),
+            .body(format!("Failed to get pipeline config versions: {}", e)),
     }
-}
\ No newline at end of file
+}
+
+/// Moves the targets of `targets.json`, where earlier versions kept them,
+/// into the database, and renames the file to `targets.json.migrated`.
+/// Returns how many targets were added.
+pub fn migrate_targets_file() -> Result<usize, String> {
+    let content = match file::read_file(TARGETS_FILE) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(format!("Failed to read targets file: {}", e)),
+    };
+    let targets: Targets = serde_json::from_str(&content)
+        .map_err(|e| format!("Failed to parse targets file: {}", e))?;
+
+    let imported = import_targets(&targets.targets)
+        .map_err(|e| format!("Failed to import targets: {}", e))?;
+    std::fs::rename(TARGETS_FILE, format!("{}.migrated", TARGETS_FILE))
+        .map_err(|e| format!("Failed to rename targets file: {}", e))?;
+
+    Ok(imported)
+}
+
+/// Stores the pipeline configuration earlier versions saved in the targets
+/// directory as the first version for each target that has none. The commit
+/// it came from is not known. Returns how many were stored.
+pub fn migrate_config_files() -> Result<usize, String> {
+    let targets = get_targets()
+        .map_err(|e| format!("Failed to get targets: {}", e))?;
+
+    let mut imported = 0;
+    for target in targets {
+        let current = get_pipeline_config(&target.name, None)
+            .map_err(|e| format!("Failed to get pipeline config of '{}': {}", target.name, e))?;
+        if current.is_some() {
+            continue;
+        }
+
+        let path = format!("{}/{}", CONFIG_DIR, file::repo_to_filename(&target.repository, &target.branch));
+        let config = match file::read_file(&path) {
+            Ok(config) => config,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(format!("Failed to read {}: {}", path, e)),
+        };
+        save_pipeline_config(&target.name, None, &config)
+            .map_err(|e| format!("Failed to save pipeline config of '{}': {}", target.name, e))?;
+        imported += 1;
+    }
+
+    Ok(imported)
+}
@@ -0,0 +1,472 @@
+use actix_web::{web, HttpResponse, Responder};
+use chrono::Utc;
+use serde_json::json;
+use std::cmp::Ordering;
+use std::time::Duration;
+use tokio::sync::{oneshot, Mutex, Notify};
+
+use crate::handlers::job::{record_job_update, JobUpdate};
+use crate::handlers::pipeline::{PendingResults, SlotPermits, Slots};
+use crate::models::job::{JobRequest, JobResult, JobStatus};
+use crate::models::worker::{RegisterWorkerRequest, Worker, WorkerStatus};
+use crate::db::operations::{
+    register_worker as save_worker, record_worker_heartbeat, get_workers, get_worker,
+    mark_stale_workers_offline, get_running_jobs_on_worker,
+    assign_job_worker, update_job_status, append_job_note, queue_job,
+    renew_job_lease, expire_job_leases, get_job_run, get_job_worker,
+};
+use crate::utils::duration;
+
+/// Hands jobs out to registered workers without giving any worker more
+/// jobs than its capacity. Jobs are pushed to workers with a URL and wait
+/// in a queue for pull workers to lease them.
+pub struct WorkerPool {
+    // Shared by every request to workers
+    client: reqwest::Client,
+    // Held while a worker is picked and the job recorded as running on it,
+    // so two jobs can't both take a worker's last free slot
+    selection: Mutex<()>,
+    // Signalled whenever a worker may have a free slot again
+    freed: Notify,
+    // Jobs waiting for a worker, oldest first
+    queue: std::sync::Mutex<Vec<QueuedJob>>,
+}
+
+struct QueuedJob {
+    request: JobRequest,
+    // Taken by a pull worker only along with the job
+    slots: Slots,
+    leased: oneshot::Sender<SlotPermits>,
+}
+
+/// How a job reached its worker.
+pub enum Assignment {
+    /// Pushed to the worker at this URL.
+    Push(String),
+    /// Leased by a pull worker, which reports back through its lease.
+    Leased,
+}
+
+// Takes a job out of the queue once it no longer waits, including when
+// the waiting task is aborted
+struct QueueEntry<'a> {
+    pool: &'a WorkerPool,
+    job_run_id: &'a str,
+}
+
+impl Drop for QueueEntry<'_> {
+    fn drop(&mut self) {
+        self.pool.queue.lock().unwrap().retain(|queued| queued.request.id != self.job_run_id);
+    }
+}
+
+// How often a job waiting for a free worker checks again on its own
+const WORKER_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// How long a request to a worker may take, unless it sets its own timeout
+const WORKER_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+impl Default for WorkerPool {
+    fn default() -> Self {
+        WorkerPool {
+            client: reqwest::Client::builder()
+                .timeout(WORKER_REQUEST_TIMEOUT)
+                .build()
+                .expect("Failed to create HTTP client"),
+            selection: Mutex::default(),
+            freed: Notify::new(),
+            queue: std::sync::Mutex::default(),
+        }
+    }
+}
+
+impl WorkerPool {
+    /// The client for requests to workers. Requests time out after a few
+    /// seconds unless they set a timeout of their own.
+    pub fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    /// Wakes jobs waiting for a free worker.
+    pub fn release(&self) {
+        self.freed.notify_waiters();
+    }
+
+    /// Waits until the job can go to an online worker with all of its
+    /// labels and its `slots` are free. A push worker with a free slot gets
+    /// the job right away, the least loaded one first; otherwise the job
+    /// waits, queued with the reason, until a push worker frees up or a pull
+    /// worker leases it. Until any worker has registered, jobs without
+    /// labels go to `WORKER_URL`. Returns the job's slots along with where
+    /// it went.
+    pub async fn assign(
+        &self,
+        request: &JobRequest,
+        slots: &Slots,
+    ) -> rusqlite::Result<(Assignment, SlotPermits)> {
+        let job_run_id = request.id.as_str();
+        let labels = &request.job.runs_on;
+
+        let (leased, mut on_lease) = oneshot::channel();
+        self.queue.lock().unwrap().push(QueuedJob {
+            request: request.clone(),
+            slots: slots.clone(),
+            leased,
+        });
+        let _entry = QueueEntry { pool: self, job_run_id };
+
+        let mut queued_because = None;
+        loop {
+            // Created before looking so a release in between isn't missed
+            let freed = self.freed.notified();
+
+            {
+                let _selection = self.selection.lock().await;
+                if let Ok(permits) = on_lease.try_recv() {
+                    return Ok((Assignment::Leased, permits));
+                }
+
+                let workers = get_workers()?;
+                let assignment = if workers.is_empty() && labels.is_empty() {
+                    Ok((None, worker_url()))
+                } else {
+                    let matching: Vec<Worker> = workers.into_iter()
+                        .filter(|worker| has_labels(worker, labels))
+                        .collect();
+                    let reason = queue_reason(labels, &matching);
+
+                    matching.into_iter()
+                        .filter(|worker| worker.url.is_some() && has_free_slot(worker))
+                        .min_by(compare_load)
+                        .and_then(|worker| Some((Some(worker.id), worker.url?)))
+                        .ok_or(reason)
+                };
+                let assignment = assignment.and_then(|found| Ok((found, slots.try_take()?)));
+
+                match assignment {
+                    Ok(((worker_id, url), permits)) => {
+                        assign_job_worker(job_run_id, worker_id.as_deref(), None)?;
+                        update_job_status(job_run_id, JobStatus::Running, None)?;
+                        return Ok((Assignment::Push(url), permits));
+                    }
+                    Err(reason) => {
+                        if queued_because.as_ref() != Some(&reason) {
+                            queue_job(job_run_id, &reason)?;
+                            queued_because = Some(reason);
+                        }
+                    }
+                }
+            }
+
+            tokio::select! {
+                _ = tokio::time::timeout(WORKER_POLL_INTERVAL, freed) => {}
+                Ok(permits) = &mut on_lease => return Ok((Assignment::Leased, permits)),
+            }
+        }
+    }
+
+    /// Leases the oldest queued job that `worker` can run and whose slots
+    /// are free, marking it as running on the worker until the lease
+    /// expires.
+    async fn lease(&self, worker_id: &str) -> rusqlite::Result<Option<(JobRequest, chrono::DateTime<Utc>)>> {
+        let _selection = self.selection.lock().await;
+
+        let Some(worker) = get_worker(worker_id)? else {
+            return Ok(None);
+        };
+        if !has_free_slot(&worker) {
+            return Ok(None);
+        }
+
+        loop {
+            let request = {
+                let mut queue = self.queue.lock().unwrap();
+                let Some((position, permits)) = queue.iter()
+                    .enumerate()
+                    .filter(|(_, queued)| has_labels(&worker, &queued.request.job.runs_on))
+                    .find_map(|(position, queued)| Some((position, queued.slots.try_take().ok()?)))
+                else {
+                    return Ok(None);
+                };
+
+                // A job whose run was stopped in the meantime is skipped
+                let queued = queue.remove(position);
+                match queued.leased.send(permits) {
+                    Ok(()) => queued.request,
+                    Err(_) => continue,
+                }
+            };
+
+            let expires_at = lease_deadline();
+            assign_job_worker(&request.id, Some(worker_id), Some(expires_at))?;
+            update_job_status(&request.id, JobStatus::Running, None)?;
+            return Ok(Some((request, expires_at)));
+        }
+    }
+}
+
+fn has_labels(worker: &Worker, labels: &[String]) -> bool {
+    labels.iter().all(|label| worker.labels.contains(label))
+}
+
+fn has_free_slot(worker: &Worker) -> bool {
+    worker.status == WorkerStatus::Online && worker.running_jobs < worker.capacity
+}
+
+// Explains why none of the workers that have a job's labels can take it
+fn queue_reason(labels: &[String], matching: &[Worker]) -> String {
+    let workers = if labels.is_empty() {
+        "worker".to_string()
+    } else {
+        format!("worker with labels [{}]", labels.join(", "))
+    };
+
+    if matching.is_empty() {
+        format!("No {} is registered", workers)
+    } else if matching.iter().all(|worker| worker.status == WorkerStatus::Offline) {
+        format!("Every {} is offline", workers)
+    } else if matching.iter().any(|worker| worker.url.is_none() && has_free_slot(worker)) {
+        format!("Waiting for a {} to lease the job", workers)
+    } else {
+        format!("Every online {} is busy", workers)
+    }
+}
+
+// Orders workers by the share of their capacity in use
+fn compare_load(a: &Worker, b: &Worker) -> Ordering {
+    let load_a = a.running_jobs as i64 * b.capacity as i64;
+    let load_b = b.running_jobs as i64 * a.capacity as i64;
+    load_a.cmp(&load_b)
+}
+
+/// Where jobs are sent while no worker has registered.
+pub fn worker_url() -> String {
+    std::env::var("WORKER_URL")
+        .unwrap_or_else(|_| "http://localhost:8080".to_string())
+}
+
+// How long a worker may go without a heartbeat before it is considered offline
+fn heartbeat_timeout() -> Duration {
+    duration::from_env("WORKER_HEARTBEAT_TIMEOUT", DEFAULT_HEARTBEAT_TIMEOUT)
+}
+
+const DEFAULT_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How long a lease lasts unless it is renewed
+fn lease_duration() -> Duration {
+    duration::from_env("JOB_LEASE_DURATION", DEFAULT_LEASE_DURATION)
+}
+
+const DEFAULT_LEASE_DURATION: Duration = Duration::from_secs(2 * 60);
+
+fn lease_deadline() -> chrono::DateTime<Utc> {
+    Utc::now() + chrono::Duration::from_std(lease_duration()).unwrap_or_default()
+}
+
+pub async fn register_worker(
+    request: web::Json<RegisterWorkerRequest>,
+    workers: web::Data<WorkerPool>,
+) -> impl Responder {
+    if request.url.as_deref().is_some_and(|url| url.trim().is_empty()) {
+        return HttpResponse::BadRequest().body("Worker URL must not be empty");
+    }
+    if request.capacity < 1 {
+        return HttpResponse::BadRequest().body("Worker capacity must be at least 1");
+    }
+
+    let url = request.url.as_deref().map(|url| url.trim_end_matches('/'));
+    match save_worker(request.id.as_deref(), url, &request.labels, request.capacity) {
+        Ok(worker_id) => {
+            workers.release();
+            HttpResponse::Ok().json(json!({
+                "worker_id": worker_id,
+                "heartbeat_timeout_seconds": heartbeat_timeout().as_secs(),
+                "lease_seconds": lease_duration().as_secs()
+            }))
+        }
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Failed to register worker: {}", e)),
+    }
+}
+
+pub async fn worker_heartbeat(
+    worker_id: web::Path<String>,
+    workers: web::Data<WorkerPool>,
+) -> impl Responder {
+    match record_worker_heartbeat(&worker_id) {
+        Ok(true) => {
+            // A worker coming back online may take waiting jobs
+            workers.release();
+            HttpResponse::Ok().json(json!({ "status": WorkerStatus::Online }))
+        }
+        Ok(false) => HttpResponse::NotFound()
+            .body(format!("Worker '{}' is not registered", worker_id)),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Database error: {}", e)),
+    }
+}
+
+pub async fn list_workers() -> impl Responder {
+    match get_workers() {
+        Ok(workers) => HttpResponse::Ok().json(workers),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Database error: {}", e)),
+    }
+}
+
+/// Hands the oldest queued job the worker can run to a pull worker, or
+/// answers 204 No Content if there is none. Also counts as a heartbeat.
+pub async fn lease_job(
+    worker_id: web::Path<String>,
+    workers: web::Data<WorkerPool>,
+) -> impl Responder {
+    match record_worker_heartbeat(&worker_id) {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::NotFound()
+            .body(format!("Worker '{}' is not registered", worker_id)),
+        Err(e) => return HttpResponse::InternalServerError()
+            .body(format!("Database error: {}", e)),
+    }
+
+    match workers.lease(&worker_id).await {
+        Ok(Some((job, expires_at))) => HttpResponse::Ok().json(json!({
+            "job": job,
+            "lease_expires_at": expires_at
+        })),
+        Ok(None) => HttpResponse::NoContent().finish(),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Failed to lease a job: {}", e)),
+    }
+}
+
+/// Extends the worker's lease on a job. A 409 Conflict tells the worker
+/// to stop the job, e.g. because it was cancelled or the lease expired.
+pub async fn renew_lease(path: web::Path<(String, String)>) -> impl Responder {
+    let (worker_id, job_id) = path.into_inner();
+    if let Err(e) = record_worker_heartbeat(&worker_id) {
+        return HttpResponse::InternalServerError()
+            .body(format!("Database error: {}", e));
+    }
+
+    let expires_at = lease_deadline();
+    match renew_job_lease(&job_id, &worker_id, expires_at) {
+        Ok(true) => HttpResponse::Ok().json(json!({ "lease_expires_at": expires_at })),
+        Ok(false) => lease_lost(&worker_id, &job_id),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Failed to renew lease: {}", e)),
+    }
+}
+
+/// Reports the result of a leased job, which ends the lease.
+pub async fn complete_lease(
+    path: web::Path<(String, String)>,
+    update: web::Json<JobUpdate>,
+    pending_results: web::Data<PendingResults>,
+) -> impl Responder {
+    let (worker_id, job_id) = path.into_inner();
+    if !update.status.is_finished() {
+        return HttpResponse::BadRequest()
+            .body(format!("'{}' is not a final job status", update.status));
+    }
+
+    match (get_job_run(&job_id), get_job_worker(&job_id)) {
+        (Ok(Some(job_run)), Ok((Some(holder), _)))
+            if job_run.status == JobStatus::Running && holder == worker_id => {}
+        (Ok(None), _) => return HttpResponse::NotFound()
+            .body(format!("Job '{}' not found", job_id)),
+        (Err(e), _) | (_, Err(e)) => return HttpResponse::InternalServerError()
+            .body(format!("Database error: {}", e)),
+        _ => return lease_lost(&worker_id, &job_id),
+    }
+
+    match record_job_update(&job_id, &update, &pending_results) {
+        Ok(()) => HttpResponse::Ok().json(json!({
+            "status": "completed",
+            "job_id": job_id
+        })),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Failed to update job status: {}", e)),
+    }
+}
+
+fn lease_lost(worker_id: &str, job_id: &str) -> HttpResponse {
+    HttpResponse::Conflict()
+        .body(format!("Worker '{}' does not hold a lease on job '{}'", worker_id, job_id))
+}
+
+/// Marks workers that stopped sending heartbeats as offline and puts jobs
+/// whose lease expired back in the queue. The jobs offline workers were
+/// running end with a worker error, which their retry policy may
+/// reschedule on another worker.
+pub async fn watch_workers(pending_results: web::Data<PendingResults>) {
+    let timeout = heartbeat_timeout();
+    let interval = (timeout / 3).max(Duration::from_secs(1));
+
+    loop {
+        tokio::time::sleep(interval).await;
+        expire_leases(&pending_results);
+
+        let cutoff = Utc::now() - chrono::Duration::from_std(timeout).unwrap_or_default();
+        let offline = match mark_stale_workers_offline(cutoff) {
+            Ok(offline) => offline,
+            Err(e) => {
+                eprintln!("Failed to check worker heartbeats: {}", e);
+                continue;
+            }
+        };
+
+        for worker_id in offline {
+            eprintln!("Worker {} stopped sending heartbeats", worker_id);
+            let job_ids = match get_running_jobs_on_worker(&worker_id) {
+                Ok(job_ids) => job_ids,
+                Err(e) => {
+                    eprintln!("Failed to load jobs of worker {}: {}", worker_id, e);
+                    continue;
+                }
+            };
+
+            let reason = format!("Worker {} went offline", worker_id);
+            for job_id in job_ids {
+                let result = JobResult {
+                    id: job_id,
+                    status: JobStatus::Error,
+                    output: String::new(),
+                    artifacts: vec![],
+                    note: Some(reason.clone()),
+                };
+
+                // Jobs nobody waits for any more are recorded directly
+                if let Err(result) = pending_results.deliver(result) {
+                    if let Err(e) = append_job_note(&result.id, &reason)
+                        .and_then(|_| update_job_status(&result.id, result.status, None))
+                    {
+                        eprintln!("Failed to update job status: {}", e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Hands jobs whose lease ran out back to their executor, which queues them
+// again
+fn expire_leases(pending_results: &PendingResults) {
+    let expired = match expire_job_leases(Utc::now()) {
+        Ok(expired) => expired,
+        Err(e) => {
+            eprintln!("Failed to expire job leases: {}", e);
+            return;
+        }
+    };
+
+    for job_id in expired {
+        eprintln!("Lease on job {} expired", job_id);
+        if !pending_results.requeue(&job_id) {
+            if let Err(e) = append_job_note(&job_id, "Lease expired")
+                .and_then(|_| update_job_status(&job_id, JobStatus::Error, None))
+            {
+                eprintln!("Failed to update job status: {}", e);
+            }
+        }
+    }
+}
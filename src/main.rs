@@ -1,17 +1,39 @@
 use actix_web::{web, App, HttpServer};
 use actix_cors::Cors;
-use std::sync::Mutex;
 
 mod models;
 mod db;
 mod handlers;
 mod utils;
+mod storage;
 
 use crate::handlers::{
-    pipeline::{trigger_build, get_status},
-    target::{add_target, list_targets, get_target_pipeline},
+    pipeline::{
+        trigger_build, trigger_target, get_status, cancel_run, rerun_run, retry_job, resume_runs,
+        pin_run, unpin_run,
+        ActiveRuns, JobSlots, PendingResults,
+    },
+    job::{update_job, get_job_details},
+    artifact::{
+        upload_artifact,
+        upload_artifacts,
+        list_artifacts,
+        download_artifact,
+        download_run_artifacts,
+        download_latest_artifact,
+    },
+    log::{get_job_logs, append_job_log, stream_job_logs, LogStreams},
+    target::{
+        add_target, list_targets, update_target, delete_target, refresh_target,
+        get_target_pipeline, list_pipeline_versions,
+        migrate_targets_file, migrate_config_files,
+    },
+    secret::{put_secret, list_secrets, get_secret, delete_secret},
+    worker::{
+        register_worker, worker_heartbeat, list_workers,
+        lease_job, renew_lease, complete_lease, watch_workers, WorkerPool,
+    },
 };
-use crate::utils::file;
 use crate::db::init::init_database;
 
 #[actix_web::main]
@@ -22,19 +44,33 @@ async fn main() -> std::io::Result<()> {
         return Ok(());
     }
 
-    // Ensure targets directory exists
-    if let Err(e) = file::ensure_directory("targets") {
-        eprintln!("Failed to create targets directory: {}", e);
-        return Ok(());
+    // Move artifacts saved as text by earlier versions into the artifact store
+    match storage::artifacts::migrate_text_artifacts() {
+        Ok(0) => {}
+        Ok(count) => println!("Moved {} artifacts into the artifact store", count),
+        Err(e) => {
+            eprintln!("Failed to migrate artifacts: {}", e);
+            return Ok(());
+        }
+    }
+
+    // Move the targets earlier versions kept in targets.json into the database
+    match migrate_targets_file() {
+        Ok(0) => {}
+        Ok(count) => println!("Moved {} targets into the database", count),
+        Err(e) => {
+            eprintln!("Failed to migrate targets: {}", e);
+            return Ok(());
+        }
     }
 
-    // Create empty targets.json if it doesn't exist
-    if let Err(e) = file::read_file("targets.json") {
-        if let Err(e) = file::save_file(
-            "targets.json",
-            &serde_json::to_string_pretty(&models::target::Targets { targets: vec![] }).unwrap()
-        ) {
-            eprintln!("Failed to create targets.json: {}", e);
+    // Move the pipeline configs earlier versions kept in the targets
+    // directory into the database
+    match migrate_config_files() {
+        Ok(0) => {}
+        Ok(count) => println!("Moved {} pipeline configs into the database", count),
+        Err(e) => {
+            eprintln!("Failed to migrate pipeline configs: {}", e);
             return Ok(());
         }
     }
@@ -45,8 +81,30 @@ async fn main() -> std::io::Result<()> {
         .unwrap_or_else(|_| "8000".to_string())
         .parse::<u16>()
         .expect("Invalid PORT");
+    let max_parallel_jobs = std::env::var("MAX_PARALLEL_JOBS")
+        .ok()
+        .map(|v| v.parse::<usize>().expect("Invalid MAX_PARALLEL_JOBS"));
     
-    let data = web::Data::new(Mutex::new(()));
+    let job_slots = web::Data::new(JobSlots::new(max_parallel_jobs));
+    let active_runs = web::Data::new(ActiveRuns::default());
+    let pending_results = web::Data::new(PendingResults::default());
+    let workers = web::Data::new(WorkerPool::default());
+    let log_streams = web::Data::new(LogStreams::default());
+
+    // Take workers that stop sending heartbeats out of rotation and requeue
+    // jobs whose lease expired
+    tokio::spawn(watch_workers(pending_results.clone()));
+
+    // Delete runs, logs and artifacts that retention rules no longer keep
+    tokio::spawn(storage::retention::prune_periodically());
+
+    // Pick up the runs that were in progress when the master last stopped
+    tokio::spawn(resume_runs(
+        job_slots.clone(),
+        active_runs.clone(),
+        pending_results.clone(),
+        workers.clone(),
+    ));
     
     println!("Starting server at {}:{}", host, port);
 
@@ -60,17 +118,54 @@ async fn main() -> std::io::Result<()> {
 
         App::new()
             .wrap(cors)
-            .app_data(data.clone())
+            .app_data(job_slots.clone())
+            .app_data(active_runs.clone())
+            .app_data(pending_results.clone())
+            .app_data(workers.clone())
+            .app_data(log_streams.clone())
             // Pipeline routes
             .service(
                 web::scope("/api")
                     // Pipeline execution endpoints
                     .route("/trigger", web::post().to(trigger_build))
                     .route("/pipelines/{name}/status", web::get().to(get_status))
+                    .route("/runs/{id}/cancel", web::post().to(cancel_run))
+                    .route("/runs/{id}/rerun", web::post().to(rerun_run))
+                    .route("/runs/{id}/pin", web::put().to(pin_run))
+                    .route("/runs/{id}/pin", web::delete().to(unpin_run))
+                    .route("/runs/{id}/artifacts.zip", web::get().to(download_run_artifacts))
+                    // Job endpoints, also used by workers to report results
+                    .route("/jobs/{id}", web::get().to(get_job_details))
+                    .route("/jobs/{id}", web::patch().to(update_job))
+                    .route("/jobs/{id}/logs", web::get().to(get_job_logs))
+                    .route("/jobs/{id}/logs", web::post().to(append_job_log))
+                    .route("/jobs/{id}/logs/stream", web::get().to(stream_job_logs))
+                    .route("/jobs/{id}/artifacts", web::post().to(upload_artifacts))
+                    .route("/jobs/{id}/artifacts", web::get().to(list_artifacts))
+                    .route("/jobs/{id}/artifacts/{name:.+}", web::put().to(upload_artifact))
+                    .route("/jobs/{id}/artifacts/{name:.+}", web::get().to(download_artifact))
+                    .route("/jobs/{id}/retry", web::post().to(retry_job))
+                    // Worker registry endpoints
+                    .route("/workers", web::get().to(list_workers))
+                    .route("/workers/register", web::post().to(register_worker))
+                    .route("/workers/{id}/heartbeat", web::post().to(worker_heartbeat))
+                    .route("/workers/{id}/lease", web::post().to(lease_job))
+                    .route("/workers/{id}/leases/{job_id}/renew", web::post().to(renew_lease))
+                    .route("/workers/{id}/leases/{job_id}/complete", web::post().to(complete_lease))
                     // Target management endpoints
                     .route("/targets", web::post().to(add_target))
                     .route("/targets", web::get().to(list_targets))
+                    .route("/targets/{name}", web::put().to(update_target))
+                    .route("/targets/{name}", web::delete().to(delete_target))
+                    .route("/targets/{name}/refresh", web::post().to(refresh_target))
+                    .route("/targets/{name}/trigger", web::post().to(trigger_target))
                     .route("/targets/{name}/pipeline", web::get().to(get_target_pipeline))
+                    .route("/targets/{name}/pipeline/versions", web::get().to(list_pipeline_versions))
+                    .route("/targets/{name}/secrets", web::get().to(list_secrets))
+                    .route("/targets/{name}/secrets/{key}", web::put().to(put_secret))
+                    .route("/targets/{name}/secrets/{key}", web::get().to(get_secret))
+                    .route("/targets/{name}/secrets/{key}", web::delete().to(delete_secret))
+                    .route("/targets/{name}/latest/artifacts/{artifact:.+}", web::get().to(download_latest_artifact))
             )
     })
     .bind((host, port))?
@@ -1,21 +1,67 @@
 use std::fmt;
+use std::str::FromStr;
 use serde::{Deserialize, Serialize};
 use chrono::{DateTime, Utc};
 
+use crate::models::pipeline::Job;
+
+/// Payload sent to a worker: the job definition plus the commit to build.
+/// `id` is the `job_runs` row the worker reports status for.
+#[derive(Debug, Clone, Serialize)]
+pub struct JobRequest {
+    pub id: String,
+    #[serde(flatten)]
+    pub job: Job,
+    pub commit: Option<String>,
+    /// Artifacts of the jobs in `artifacts_from`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub artifacts: Vec<InputArtifact>,
+}
+
+/// An upstream job's artifact handed to a job.
+#[derive(Debug, Clone, Serialize)]
+pub struct InputArtifact {
+    /// The job that produced it.
+    pub job: String,
+    pub name: String,
+    /// Where to download it, relative to the master's address.
+    pub url: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct JobResult {
+    /// The `job_runs` id the job was dispatched with.
+    #[serde(default)]
     pub id: String,
     pub status: JobStatus,
     pub output: String,
     pub artifacts: Vec<JobArtifact>,
+    /// Why the master ended the job itself, e.g. on a timeout. It goes at
+    /// the end of the job's log rather than replacing it.
+    #[serde(skip)]
+    pub note: Option<String>,
 }
 
+/// An artifact reported as text along with a job's result.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct JobArtifact {
     pub name: String,
     pub content: String,
 }
 
+/// A stored artifact. Its content is in the artifact store.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArtifactInfo {
+    pub name: String,
+    pub size: u64,
+    pub media_type: String,
+    /// Hex-encoded SHA-256 of the content.
+    pub sha256: String,
+    pub created_at: DateTime<Utc>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct JobRun {
     pub id: String,
@@ -26,28 +72,69 @@ pub struct JobRun {
     pub start_time: DateTime<Utc>,
     pub end_time: Option<DateTime<Utc>>,
     pub duration_seconds: Option<i64>,
+    pub attempt: i32,
+    /// Why a queued job has not been dispatched yet.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_reason: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum JobStatus {
     Pending,
+    /// The job is ready to run and waiting for a worker that can take it.
+    Queued,
     Running,
     Succeeded,
     Failed,
+    /// The job could not be run because of a worker or transport problem,
+    /// as opposed to its commands failing.
+    Error,
+    /// The job was stopped because it ran past its timeout.
+    #[serde(rename = "timed_out")]
+    TimedOut,
     Cancelled,
     Skipped,
 }
 
+impl JobStatus {
+    /// Whether the job has reached a terminal state.
+    pub fn is_finished(&self) -> bool {
+        !matches!(self, JobStatus::Pending | JobStatus::Queued | JobStatus::Running)
+    }
+}
+
 impl fmt::Display for JobStatus {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             JobStatus::Pending => write!(f, "pending"),
+            JobStatus::Queued => write!(f, "queued"),
             JobStatus::Running => write!(f, "running"),
             JobStatus::Succeeded => write!(f, "succeeded"),
             JobStatus::Failed => write!(f, "failed"),
+            JobStatus::Error => write!(f, "error"),
+            JobStatus::TimedOut => write!(f, "timed_out"),
             JobStatus::Cancelled => write!(f, "cancelled"),
             JobStatus::Skipped => write!(f, "skipped"),
         }
     }
+}
+
+impl FromStr for JobStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(JobStatus::Pending),
+            "queued" => Ok(JobStatus::Queued),
+            "running" => Ok(JobStatus::Running),
+            "succeeded" => Ok(JobStatus::Succeeded),
+            "failed" => Ok(JobStatus::Failed),
+            "error" => Ok(JobStatus::Error),
+            "timed_out" => Ok(JobStatus::TimedOut),
+            "cancelled" => Ok(JobStatus::Cancelled),
+            "skipped" => Ok(JobStatus::Skipped),
+            other => Err(format!("Unknown job status '{}'", other)),
+        }
+    }
 }
\ No newline at end of file
@@ -1,3 +1,5 @@
 pub mod job;
 pub mod pipeline;
-pub mod target;
\ No newline at end of file
+pub mod secret;
+pub mod target;
+pub mod worker;
\ No newline at end of file
@@ -1,11 +1,96 @@
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
 use serde::{Deserialize, Serialize};
 use chrono::{DateTime, Utc};
 
+use crate::models::job::JobStatus;
+use crate::utils::{duration, template};
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Pipeline {
     pub name: String,
     pub stages: Vec<Stage>,
+    /// Maximum number of this pipeline's jobs that may run at the same time.
+    #[serde(default)]
+    pub max_parallel: Option<usize>,
+    /// Upper bound on the whole run, e.g. `2h`. Falls back to the server default.
+    #[serde(default, with = "duration::timeout", skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<Duration>,
+    /// Values a build can be started with.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parameters: Vec<Parameter>,
+}
+
+/// Parameter values by name, as given to a build or as resolved for a run.
+pub type Parameters = serde_json::Map<String, serde_json::Value>;
+
+/// A value set when a build is triggered. Every job gets it as an input of
+/// the same name and as `${{ parameters.NAME }}` in its commands and inputs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Parameter {
+    pub name: String,
+    #[serde(rename = "type", default)]
+    pub kind: ParameterType,
+    /// Used when a build doesn't set the parameter. Parameters without a
+    /// default have to be set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<serde_json::Value>,
+    /// The values a `choice` parameter can take.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ParameterType {
+    #[default]
+    String,
+    Bool,
+    Choice,
+}
+
+impl fmt::Display for ParameterType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParameterType::String => write!(f, "string"),
+            ParameterType::Bool => write!(f, "bool"),
+            ParameterType::Choice => write!(f, "choice"),
+        }
+    }
+}
+
+impl Parameter {
+    /// Checks a value against the parameter's type and returns it as the
+    /// text jobs get.
+    fn check(&self, value: &serde_json::Value) -> Result<String, String> {
+        use serde_json::Value;
+
+        let text = match (self.kind, value) {
+            (ParameterType::Bool, Value::Bool(b)) => b.to_string(),
+            (ParameterType::Bool, Value::String(s)) if s == "true" || s == "false" => s.clone(),
+            (ParameterType::String | ParameterType::Choice, Value::String(s)) => s.clone(),
+            (ParameterType::String | ParameterType::Choice, Value::Number(n)) => n.to_string(),
+            (kind, value) => return Err(format!(
+                "Parameter '{}' must be a {}, not {}",
+                self.name, kind, value
+            )),
+        };
+
+        if self.kind == ParameterType::Choice && !self.options.contains(&text) {
+            return Err(format!(
+                "Parameter '{}' must be one of {}, not '{}'",
+                self.name, self.options.join(", "), text
+            ));
+        }
+        // Placeholders in a value would be filled in later, e.g. with secrets
+        if text.contains("${{") {
+            return Err(format!("Parameter '{}' can't contain '${{{{'", self.name));
+        }
+
+        Ok(text)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -24,14 +109,471 @@ pub struct Job {
     pub inputs: Vec<JobInput>,
     #[serde(default)]
     pub outputs: Vec<JobOutput>,
+    /// Jobs that must succeed before this one starts. When unset the job
+    /// waits for every job in the previous stage.
+    #[serde(default)]
+    pub needs: Option<Vec<String>>,
+    /// Jobs whose artifacts are handed to this one. They have to be among
+    /// the jobs it waits on, directly or not.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub artifacts_from: Vec<String>,
+    /// Expands this job into one job per combination of axis values.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub matrix: Option<Matrix>,
+    /// Runs the job again automatically when it fails in one of the listed ways.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryPolicy>,
+    /// Upper bound on a single try of the job, e.g. `30m`.
+    #[serde(default, with = "duration::timeout", skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<Duration>,
+    /// Labels a worker must have to run this job, e.g. `[linux, arm64]`.
+    #[serde(default, alias = "tags", skip_serializing_if = "Vec::is_empty")]
+    pub runs_on: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    /// Retries after the first try.
+    pub max: u32,
+    #[serde(default = "default_retry_on")]
+    pub on: Vec<RetryOn>,
+    #[serde(default)]
+    pub backoff: Backoff,
+    /// Wait before the first retry; exponential backoff doubles it each time.
+    #[serde(default = "default_retry_delay", with = "duration")]
+    pub delay: Duration,
+}
+
+/// The kinds of failure a retry policy can react to.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOn {
+    /// The worker could not be reached or gave an unusable answer.
+    WorkerError,
+    /// The job ran out of time.
+    Timeout,
+    /// The job's own commands failed.
+    Failure,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backoff {
+    Fixed,
+    #[default]
+    Exponential,
+}
+
+fn default_retry_on() -> Vec<RetryOn> {
+    vec![RetryOn::WorkerError]
+}
+
+fn default_retry_delay() -> Duration {
+    Duration::from_secs(10)
+}
+
+impl RetryPolicy {
+    /// How long to wait before retry number `retry` (starting at 1), or
+    /// `None` if a job that ended with `status` should not be retried.
+    pub fn delay_before(&self, retry: u32, status: &JobStatus) -> Option<Duration> {
+        let reason = match status {
+            JobStatus::Error => RetryOn::WorkerError,
+            JobStatus::Failed => RetryOn::Failure,
+            JobStatus::TimedOut => RetryOn::Timeout,
+            _ => return None,
+        };
+        if retry > self.max || !self.on.contains(&reason) {
+            return None;
+        }
+
+        Some(match self.backoff {
+            Backoff::Fixed => self.delay,
+            Backoff::Exponential => self.delay
+                .saturating_mul(2u32.saturating_pow(retry - 1)),
+        })
+    }
+}
+
+/// Axis values for a matrix job, e.g. `rust: ["1.80", stable]`, plus
+/// `include`/`exclude` entries that add or remove combinations.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Matrix {
+    #[serde(default)]
+    pub include: Vec<serde_yaml::Mapping>,
+    #[serde(default)]
+    pub exclude: Vec<serde_yaml::Mapping>,
+    #[serde(flatten)]
+    pub axes: serde_yaml::Mapping,
+}
+
+type Combination = Vec<(String, String)>;
+
+impl Matrix {
+    /// Every combination of axis values, after applying `exclude` and then
+    /// `include`. Keys keep the order they were declared in.
+    pub fn combinations(&self) -> Result<Vec<Combination>, String> {
+        let mut axes = Vec::new();
+        for (key, values) in &self.axes {
+            let values = match values {
+                serde_yaml::Value::Sequence(values) => values.iter()
+                    .map(matrix_value)
+                    .collect::<Result<Vec<_>, _>>()?,
+                value => vec![matrix_value(value)?],
+            };
+            axes.push((matrix_value(key)?, values));
+        }
+
+        let mut combinations: Vec<Combination> = if axes.is_empty() {
+            Vec::new()
+        } else {
+            vec![Vec::new()]
+        };
+        for (key, values) in &axes {
+            combinations = combinations.into_iter()
+                .flat_map(|combination| values.iter().map(move |value| {
+                    let mut combination = combination.clone();
+                    combination.push((key.clone(), value.clone()));
+                    combination
+                }))
+                .collect();
+        }
+
+        for entry in &self.exclude {
+            let entry = matrix_entry(entry)?;
+            combinations.retain(|combination| !entry.iter().all(|pair| combination.contains(pair)));
+        }
+
+        // An include entry extends every combination it agrees with on the
+        // original axes; if it agrees with none it becomes a new combination
+        let axis_names: Vec<&str> = axes.iter().map(|(key, _)| key.as_str()).collect();
+        let original = combinations.len();
+        for entry in &self.include {
+            let entry = matrix_entry(entry)?;
+            let mut matched = false;
+            for combination in combinations.iter_mut().take(original) {
+                let agrees = entry.iter()
+                    .filter(|(key, _)| axis_names.contains(&key.as_str()))
+                    .all(|pair| combination.contains(pair));
+                if agrees {
+                    matched = true;
+                    for (key, value) in &entry {
+                        match combination.iter_mut().find(|(k, _)| k == key) {
+                            Some(existing) => existing.1 = value.clone(),
+                            None => combination.push((key.clone(), value.clone())),
+                        }
+                    }
+                }
+            }
+            if !matched {
+                combinations.push(entry);
+            }
+        }
+
+        Ok(combinations)
+    }
+}
+
+fn matrix_entry(entry: &serde_yaml::Mapping) -> Result<Combination, String> {
+    entry.iter()
+        .map(|(key, value)| Ok((matrix_value(key)?, matrix_value(value)?)))
+        .collect()
+}
+
+fn matrix_value(value: &serde_yaml::Value) -> Result<String, String> {
+    match value {
+        serde_yaml::Value::String(s) => Ok(s.clone()),
+        serde_yaml::Value::Number(n) => Ok(n.to_string()),
+        serde_yaml::Value::Bool(b) => Ok(b.to_string()),
+        other => Err(format!("Unsupported matrix value: {:?}", other)),
+    }
+}
+
+impl Job {
+    /// A concrete copy of this job for one matrix combination, named like
+    /// `test (rust=1.80, os=linux)` with `${{ matrix.* }}` filled in.
+    fn for_combination(&self, combination: &Combination) -> Job {
+        let label = combination.iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        Job {
+            name: format!("{} ({})", self.name, label),
+            commands: self.commands.iter()
+                .map(|command| template::render(command, "matrix", combination))
+                .collect(),
+            inputs: self.inputs.iter()
+                .map(|input| JobInput {
+                    value: template::render(&input.value, "matrix", combination),
+                    from_output: input.from_output.as_ref().map(|output| OutputRef {
+                        job: template::render(&output.job, "matrix", combination),
+                        ..output.clone()
+                    }),
+                    ..input.clone()
+                })
+                .collect(),
+            artifacts_from: self.artifacts_from.iter()
+                .map(|name| template::render(name, "matrix", combination))
+                .collect(),
+            runs_on: self.runs_on.iter()
+                .map(|label| template::render(label, "matrix", combination))
+                .collect(),
+            matrix: None,
+            ..self.clone()
+        }
+    }
+}
+
+impl Pipeline {
+    /// Replaces every matrix job with its concrete jobs. A `needs` entry
+    /// naming a matrix job then refers to all of its expansions.
+    pub fn expand_matrices(&mut self) -> Result<(), String> {
+        let mut expanded_names: HashMap<String, Vec<String>> = HashMap::new();
+
+        for stage in &mut self.stages {
+            let mut jobs = Vec::new();
+            for job in stage.jobs.drain(..) {
+                let Some(matrix) = &job.matrix else {
+                    jobs.push(job);
+                    continue;
+                };
+
+                let combinations = matrix.combinations()
+                    .map_err(|e| format!("Invalid matrix for job '{}': {}", job.name, e))?;
+                if combinations.is_empty() {
+                    return Err(format!("Matrix for job '{}' produces no jobs", job.name));
+                }
+
+                let expansions: Vec<Job> = combinations.iter()
+                    .map(|combination| job.for_combination(combination))
+                    .collect();
+                expanded_names.insert(
+                    job.name.clone(),
+                    expansions.iter().map(|j| j.name.clone()).collect(),
+                );
+                jobs.extend(expansions);
+            }
+            stage.jobs = jobs;
+        }
+
+        let expand = |names: &mut Vec<String>| {
+            *names = names.drain(..)
+                .flat_map(|name| match expanded_names.get(&name) {
+                    Some(names) => names.clone(),
+                    None => vec![name],
+                })
+                .collect();
+        };
+        for job in self.stages.iter_mut().flat_map(|stage| stage.jobs.iter_mut()) {
+            if let Some(needs) = &mut job.needs {
+                expand(needs);
+            }
+            expand(&mut job.artifacts_from);
+        }
+
+        Ok(())
+    }
+
+    /// Checks the values a build was started with against the declared
+    /// parameters, fills in defaults and merges the result into every job's
+    /// inputs. A job input named like a parameter takes its value. Returns
+    /// the values used.
+    pub fn apply_parameters(&mut self, values: &Parameters) -> Result<Parameters, String> {
+        let mut seen = HashSet::new();
+        for parameter in &self.parameters {
+            if !seen.insert(parameter.name.as_str()) {
+                return Err(format!("Duplicate parameter '{}'", parameter.name));
+            }
+            if parameter.kind == ParameterType::Choice && parameter.options.is_empty() {
+                return Err(format!("Choice parameter '{}' has no options", parameter.name));
+            }
+        }
+        if let Some(name) = values.keys().find(|name| !seen.contains(name.as_str())) {
+            return Err(format!("Unknown parameter '{}'", name));
+        }
+
+        let mut resolved = Vec::new();
+        for parameter in &self.parameters {
+            let value = match values.get(&parameter.name).or(parameter.default.as_ref()) {
+                Some(value) => parameter.check(value)?,
+                None => return Err(format!("Parameter '{}' is required", parameter.name)),
+            };
+            resolved.push((parameter.name.clone(), value));
+        }
+
+        for job in self.stages.iter_mut().flat_map(|stage| stage.jobs.iter_mut()) {
+            for command in &mut job.commands {
+                *command = template::render(command, "parameters", &resolved);
+            }
+            for input in &mut job.inputs {
+                input.value = template::render(&input.value, "parameters", &resolved);
+            }
+
+            for (name, value) in &resolved {
+                match job.inputs.iter_mut().find(|input| input.name == *name) {
+                    Some(input) => {
+                        input.value = value.clone();
+                        input.from_secret = None;
+                        input.from_output = None;
+                    }
+                    None => job.inputs.push(JobInput {
+                        name: name.clone(),
+                        value: value.clone(),
+                        secret: false,
+                        from_secret: None,
+                        from_output: None,
+                    }),
+                }
+            }
+        }
+
+        Ok(resolved.into_iter()
+            .map(|(name, value)| (name, serde_json::Value::String(value)))
+            .collect())
+    }
+
+    /// All jobs in stage order. A job's position here is its `job_index`.
+    pub fn jobs(&self) -> impl Iterator<Item = &Job> {
+        self.stages.iter().flat_map(|stage| stage.jobs.iter())
+    }
+
+    /// Resolves the jobs each job waits on, as indexes into `jobs()`.
+    /// Rejects duplicate job names, unknown `needs` entries and cycles.
+    pub fn dependencies(&self) -> Result<Vec<Vec<usize>>, String> {
+        let mut index_by_name = HashMap::new();
+        for (index, job) in self.jobs().enumerate() {
+            if index_by_name.insert(job.name.as_str(), index).is_some() {
+                return Err(format!("Duplicate job name '{}'", job.name));
+            }
+        }
+
+        let mut dependencies = Vec::new();
+        let mut previous_stage: Vec<usize> = Vec::new();
+        let mut index = 0;
+        for stage in &self.stages {
+            let mut current_stage = Vec::new();
+            for job in &stage.jobs {
+                let needs = match &job.needs {
+                    Some(needs) => needs.iter()
+                        .map(|name| index_by_name.get(name.as_str()).copied().ok_or_else(|| {
+                            format!("Job '{}' needs unknown job '{}'", job.name, name)
+                        }))
+                        .collect::<Result<Vec<_>, _>>()?,
+                    None => previous_stage.clone(),
+                };
+                dependencies.push(needs);
+                current_stage.push(index);
+                index += 1;
+            }
+            // Empty stages don't break the chain between their neighbours
+            if !current_stage.is_empty() {
+                previous_stage = current_stage;
+            }
+        }
+
+        // Kahn's algorithm: anything left unvisited sits on a cycle
+        let mut waiting_on: Vec<usize> = dependencies.iter().map(|d| d.len()).collect();
+        let mut dependents = vec![Vec::new(); dependencies.len()];
+        for (job, needs) in dependencies.iter().enumerate() {
+            for &need in needs {
+                dependents[need].push(job);
+            }
+        }
+        let mut ready: VecDeque<usize> = (0..dependencies.len())
+            .filter(|&job| waiting_on[job] == 0)
+            .collect();
+        let mut visited = 0;
+        while let Some(job) = ready.pop_front() {
+            visited += 1;
+            for &dependent in &dependents[job] {
+                waiting_on[dependent] -= 1;
+                if waiting_on[dependent] == 0 {
+                    ready.push_back(dependent);
+                }
+            }
+        }
+
+        if visited < dependencies.len() {
+            let cycle: Vec<&str> = self.jobs()
+                .zip(&waiting_on)
+                .filter(|(_, &waiting)| waiting > 0)
+                .map(|(job, _)| job.name.as_str())
+                .collect();
+            return Err(format!("Dependency cycle between jobs: {}", cycle.join(", ")));
+        }
+
+        self.check_artifact_sources(&index_by_name, &dependencies)?;
+
+        Ok(dependencies)
+    }
+
+    // A job can only take artifacts from jobs that are done before it starts
+    fn check_artifact_sources(
+        &self,
+        index_by_name: &HashMap<&str, usize>,
+        dependencies: &[Vec<usize>],
+    ) -> Result<(), String> {
+        for (index, job) in self.jobs().enumerate() {
+            let sources = job.artifacts_from.iter()
+                .chain(job.inputs.iter().filter_map(|input| input.from_output.as_ref().map(|o| &o.job)));
+
+            for source in sources {
+                let Some(&source_index) = index_by_name.get(source.as_str()) else {
+                    return Err(format!("Job '{}' takes artifacts from unknown job '{}'", job.name, source));
+                };
+
+                let mut upstream = dependencies[index].clone();
+                let mut seen = HashSet::new();
+                let mut found = false;
+                while let Some(need) = upstream.pop() {
+                    if need == source_index {
+                        found = true;
+                        break;
+                    }
+                    if seen.insert(need) {
+                        upstream.extend(&dependencies[need]);
+                    }
+                }
+                if !found {
+                    return Err(format!(
+                        "Job '{}' takes artifacts from '{}', which it does not wait for",
+                        job.name, source
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct JobInput {
     pub name: String,
+    #[serde(default)]
     pub value: String,
+    /// Masks the value in the job's log.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub secret: bool,
+    /// Takes the value from the server-side secret of this name when the
+    /// job is sent to a worker. Such inputs are always masked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from_secret: Option<String>,
+    /// Takes the value from an upstream job's output: the path to download
+    /// it from, relative to the master's address.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from_output: Option<OutputRef>,
 }
 
+/// An output of another job in the same pipeline.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutputRef {
+    pub job: String,
+    pub output: String,
+}
+
+/// A file the job produces. Workers upload it as an artifact named `name`
+/// before reporting the job's result.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct JobOutput {
     pub name: String,
@@ -44,10 +586,22 @@ pub struct PipelineRun {
     pub pipeline_name: String,
     pub repository: String,
     pub branch: String,
+    pub commit_sha: Option<String>,
     pub status: PipelineStatus,
     pub start_time: DateTime<Utc>,
     pub end_time: Option<DateTime<Utc>>,
     pub duration_seconds: Option<i64>,
+    /// The run this one re-runs, if any.
+    pub parent_run_id: Option<String>,
+    pub attempt: i32,
+    /// Pinned runs are never pruned.
+    pub pinned: bool,
+    /// The target the run was triggered through, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// Parameter values the run was started with.
+    #[serde(skip_serializing_if = "Parameters::is_empty")]
+    pub parameters: Parameters,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
@@ -58,6 +612,9 @@ pub enum PipelineStatus {
     Completed,
     Failed,
     Cancelled,
+    /// The master stopped while the run was in progress and could not
+    /// resume it.
+    Interrupted,
 }
 
 impl fmt::Display for PipelineStatus {
@@ -68,6 +625,364 @@ impl fmt::Display for PipelineStatus {
             PipelineStatus::Completed => write!(f, "completed"),
             PipelineStatus::Failed => write!(f, "failed"),
             PipelineStatus::Cancelled => write!(f, "cancelled"),
+            PipelineStatus::Interrupted => write!(f, "interrupted"),
         }
     }
-}
\ No newline at end of file
+}
+
+impl FromStr for PipelineStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(PipelineStatus::Pending),
+            "running" => Ok(PipelineStatus::Running),
+            "completed" => Ok(PipelineStatus::Completed),
+            "failed" => Ok(PipelineStatus::Failed),
+            "cancelled" => Ok(PipelineStatus::Cancelled),
+            "interrupted" => Ok(PipelineStatus::Interrupted),
+            other => Err(format!("Unknown pipeline status '{}'", other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pipeline(yaml: &str) -> Pipeline {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn jobs_without_needs_wait_for_the_previous_stage() {
+        let pipeline = pipeline("
+name: p
+stages:
+  - name: build
+    jobs:
+      - { name: a, repository: r, branch: main, commands: [] }
+      - { name: b, repository: r, branch: main, commands: [] }
+  - name: empty
+    jobs: []
+  - name: test
+    jobs:
+      - { name: c, repository: r, branch: main, commands: [] }
+      - { name: d, repository: r, branch: main, commands: [], needs: [a] }
+      - { name: e, repository: r, branch: main, commands: [], needs: [] }
+");
+        assert_eq!(
+            pipeline.dependencies().unwrap(),
+            vec![vec![], vec![], vec![0, 1], vec![0], vec![]]
+        );
+    }
+
+    #[test]
+    fn needs_naming_an_unknown_job_is_rejected() {
+        let pipeline = pipeline("
+name: p
+stages:
+  - name: build
+    jobs:
+      - { name: a, repository: r, branch: main, commands: [], needs: [missing] }
+");
+        assert_eq!(
+            pipeline.dependencies().unwrap_err(),
+            "Job 'a' needs unknown job 'missing'"
+        );
+    }
+
+    #[test]
+    fn dependency_cycles_are_rejected() {
+        let pipeline = pipeline("
+name: p
+stages:
+  - name: build
+    jobs:
+      - { name: a, repository: r, branch: main, commands: [] }
+      - { name: b, repository: r, branch: main, commands: [], needs: [a, d] }
+      - { name: c, repository: r, branch: main, commands: [], needs: [b] }
+      - { name: d, repository: r, branch: main, commands: [], needs: [c] }
+");
+        assert_eq!(
+            pipeline.dependencies().unwrap_err(),
+            "Dependency cycle between jobs: b, c, d"
+        );
+    }
+
+    #[test]
+    fn duplicate_job_names_are_rejected() {
+        let pipeline = pipeline("
+name: p
+stages:
+  - name: build
+    jobs:
+      - { name: a, repository: r, branch: main, commands: [] }
+  - name: test
+    jobs:
+      - { name: a, repository: r, branch: main, commands: [] }
+");
+        assert_eq!(pipeline.dependencies().unwrap_err(), "Duplicate job name 'a'");
+    }
+
+    fn matrix(yaml: &str) -> Matrix {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn combination(pairs: &[(&str, &str)]) -> Combination {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn matrix_combines_every_axis_value_in_declared_order() {
+        let matrix = matrix(r#"{ os: [linux, mac], rust: [stable, "1.80"], debug: true, jobs: 4 }"#);
+        assert_eq!(matrix.combinations().unwrap(), vec![
+            combination(&[("os", "linux"), ("rust", "stable"), ("debug", "true"), ("jobs", "4")]),
+            combination(&[("os", "linux"), ("rust", "1.80"), ("debug", "true"), ("jobs", "4")]),
+            combination(&[("os", "mac"), ("rust", "stable"), ("debug", "true"), ("jobs", "4")]),
+            combination(&[("os", "mac"), ("rust", "1.80"), ("debug", "true"), ("jobs", "4")]),
+        ]);
+    }
+
+    #[test]
+    fn matrix_exclude_removes_combinations_that_match_every_key() {
+        let matrix = matrix("
+os: [linux, mac]
+rust: [stable, beta]
+exclude:
+  - { os: mac, rust: beta }
+  - { os: windows }
+");
+        assert_eq!(matrix.combinations().unwrap(), vec![
+            combination(&[("os", "linux"), ("rust", "stable")]),
+            combination(&[("os", "linux"), ("rust", "beta")]),
+            combination(&[("os", "mac"), ("rust", "stable")]),
+        ]);
+    }
+
+    #[test]
+    fn matrix_include_extends_matching_combinations_or_adds_one() {
+        let matrix = matrix("
+os: [linux, mac]
+rust: [stable, beta]
+exclude:
+  - { rust: beta }
+include:
+  - { os: linux, target: musl }
+  - { os: mac, rust: stable, target: darwin }
+  - { os: windows, rust: stable }
+  - { rust: beta, experimental: true }
+");
+        assert_eq!(matrix.combinations().unwrap(), vec![
+            combination(&[("os", "linux"), ("rust", "stable"), ("target", "musl")]),
+            combination(&[("os", "mac"), ("rust", "stable"), ("target", "darwin")]),
+            combination(&[("os", "windows"), ("rust", "stable")]),
+            combination(&[("rust", "beta"), ("experimental", "true")]),
+        ]);
+    }
+
+    #[test]
+    fn matrix_rejects_nested_values() {
+        let matrix = matrix("{ os: [[linux]] }");
+        assert!(matrix.combinations().is_err());
+    }
+
+    #[test]
+    fn matrix_jobs_expand_and_needs_follow_them() {
+        let mut pipeline = pipeline("
+name: p
+stages:
+  - name: test
+    jobs:
+      - name: test
+        repository: r
+        branch: main
+        commands: [\"cargo test --target ${{ matrix.os }}\"]
+        runs_on: [\"${{ matrix.os }}\"]
+        matrix: { os: [linux, mac] }
+  - name: deploy
+    jobs:
+      - { name: deploy, repository: r, branch: main, commands: [], needs: [test], artifacts_from: [test] }
+");
+        pipeline.expand_matrices().unwrap();
+
+        let jobs: Vec<&Job> = pipeline.jobs().collect();
+        let names: Vec<&str> = jobs.iter().map(|job| job.name.as_str()).collect();
+        assert_eq!(names, ["test (os=linux)", "test (os=mac)", "deploy"]);
+        assert_eq!(jobs[1].commands, ["cargo test --target mac"]);
+        assert_eq!(jobs[1].runs_on, ["mac"]);
+        assert!(jobs[1].matrix.is_none());
+        assert_eq!(jobs[2].needs.as_deref().unwrap(), ["test (os=linux)", "test (os=mac)"]);
+        assert_eq!(jobs[2].artifacts_from, ["test (os=linux)", "test (os=mac)"]);
+        assert_eq!(pipeline.dependencies().unwrap()[2], vec![0, 1]);
+    }
+
+    #[test]
+    fn matrix_without_combinations_is_rejected() {
+        let mut pipeline = pipeline("
+name: p
+stages:
+  - name: test
+    jobs:
+      - { name: test, repository: r, branch: main, commands: [], matrix: { os: [linux], exclude: [{ os: linux }] } }
+");
+        assert_eq!(
+            pipeline.expand_matrices().unwrap_err(),
+            "Matrix for job 'test' produces no jobs"
+        );
+    }
+
+    fn retry_policy(backoff: Backoff, delay: Duration) -> RetryPolicy {
+        RetryPolicy { max: 3, on: vec![RetryOn::Failure, RetryOn::Timeout], backoff, delay }
+    }
+
+    #[test]
+    fn retries_wait_the_fixed_delay_up_to_max() {
+        let policy = retry_policy(Backoff::Fixed, Duration::from_secs(5));
+        let delays: Vec<_> = (1..=4).map(|retry| policy.delay_before(retry, &JobStatus::Failed)).collect();
+        assert_eq!(delays, [
+            Some(Duration::from_secs(5)),
+            Some(Duration::from_secs(5)),
+            Some(Duration::from_secs(5)),
+            None,
+        ]);
+    }
+
+    #[test]
+    fn exponential_backoff_doubles_the_delay() {
+        let policy = retry_policy(Backoff::Exponential, Duration::from_secs(10));
+        let delays: Vec<_> = (1..=3).map(|retry| policy.delay_before(retry, &JobStatus::TimedOut)).collect();
+        assert_eq!(delays, [
+            Some(Duration::from_secs(10)),
+            Some(Duration::from_secs(20)),
+            Some(Duration::from_secs(40)),
+        ]);
+    }
+
+    #[test]
+    fn exponential_backoff_saturates_instead_of_overflowing() {
+        let policy = RetryPolicy {
+            max: u32::MAX,
+            ..retry_policy(Backoff::Exponential, Duration::from_secs(u64::MAX / 2))
+        };
+        assert_eq!(policy.delay_before(3, &JobStatus::Failed), Some(Duration::MAX));
+        assert_eq!(policy.delay_before(u32::MAX, &JobStatus::Failed), Some(Duration::MAX));
+    }
+
+    #[test]
+    fn only_the_listed_failures_are_retried() {
+        let policy = retry_policy(Backoff::Fixed, Duration::from_secs(1));
+        assert!(policy.delay_before(1, &JobStatus::Error).is_none());
+        assert!(policy.delay_before(1, &JobStatus::Succeeded).is_none());
+        assert!(policy.delay_before(1, &JobStatus::Cancelled).is_none());
+        assert!(policy.delay_before(1, &JobStatus::Failed).is_some());
+    }
+
+    fn parameter(yaml: &str) -> Parameter {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn bool_parameters_take_booleans_or_their_text() {
+        let parameter = parameter("{ name: dry_run, type: bool }");
+        assert_eq!(parameter.check(&serde_json::json!(true)), Ok("true".to_string()));
+        assert_eq!(parameter.check(&serde_json::json!("false")), Ok("false".to_string()));
+        assert_eq!(
+            parameter.check(&serde_json::json!("yes")),
+            Err("Parameter 'dry_run' must be a bool, not \"yes\"".to_string())
+        );
+        assert!(parameter.check(&serde_json::json!(1)).is_err());
+    }
+
+    #[test]
+    fn choice_parameters_take_one_of_their_options() {
+        let parameter = parameter("{ name: environment, type: choice, options: [staging, production] }");
+        assert_eq!(parameter.check(&serde_json::json!("production")), Ok("production".to_string()));
+        assert_eq!(
+            parameter.check(&serde_json::json!("dev")),
+            Err("Parameter 'environment' must be one of staging, production, not 'dev'".to_string())
+        );
+        assert!(parameter.check(&serde_json::json!(true)).is_err());
+    }
+
+    #[test]
+    fn string_parameters_take_text_and_numbers_but_no_placeholders() {
+        let parameter = parameter("{ name: version }");
+        assert_eq!(parameter.check(&serde_json::json!("1.4.0")), Ok("1.4.0".to_string()));
+        assert_eq!(parameter.check(&serde_json::json!(3)), Ok("3".to_string()));
+        assert!(parameter.check(&serde_json::json!("${{ secrets.TOKEN }}")).is_err());
+        assert!(parameter.check(&serde_json::json!(["1.4.0"])).is_err());
+    }
+
+    fn parameterized_pipeline() -> Pipeline {
+        pipeline("
+name: p
+parameters:
+  - { name: environment, type: choice, options: [staging, production], default: staging }
+  - { name: version }
+stages:
+  - name: deploy
+    jobs:
+      - name: deploy
+        repository: r
+        branch: main
+        commands: [\"./deploy --env ${{ parameters.environment }} ${{ parameters.missing }}\"]
+        inputs:
+          - { name: version, from_secret: VERSION }
+          - { name: label, value: \"v${{ parameters.version }}\" }
+")
+    }
+
+    #[test]
+    fn parameters_fill_in_jobs_and_override_inputs() {
+        let mut pipeline = parameterized_pipeline();
+        let values: Parameters = serde_json::from_str(r#"{"version": "1.4.0"}"#).unwrap();
+
+        let resolved = pipeline.apply_parameters(&values).unwrap();
+        assert_eq!(
+            serde_json::Value::Object(resolved),
+            serde_json::json!({"environment": "staging", "version": "1.4.0"})
+        );
+
+        let job = pipeline.jobs().next().unwrap();
+        assert_eq!(job.commands, ["./deploy --env staging ${{ parameters.missing }}"]);
+        let inputs: Vec<(&str, &str)> = job.inputs.iter()
+            .map(|input| (input.name.as_str(), input.value.as_str()))
+            .collect();
+        assert_eq!(inputs, [("version", "1.4.0"), ("label", "v1.4.0"), ("environment", "staging")]);
+        assert!(job.inputs[0].from_secret.is_none());
+    }
+
+    #[test]
+    fn parameters_must_be_declared_and_set() {
+        let values: Parameters = serde_json::from_str(r#"{"version": "1", "region": "eu"}"#).unwrap();
+        assert_eq!(
+            parameterized_pipeline().apply_parameters(&values).unwrap_err(),
+            "Unknown parameter 'region'"
+        );
+        assert_eq!(
+            parameterized_pipeline().apply_parameters(&Parameters::new()).unwrap_err(),
+            "Parameter 'version' is required"
+        );
+    }
+
+    #[test]
+    fn parameter_declarations_are_checked() {
+        let mut duplicated = pipeline("
+name: p
+parameters: [{ name: version }, { name: version }]
+stages: []
+");
+        assert_eq!(duplicated.apply_parameters(&Parameters::new()).unwrap_err(), "Duplicate parameter 'version'");
+
+        let mut no_options = pipeline("
+name: p
+parameters: [{ name: environment, type: choice, default: staging }]
+stages: []
+");
+        assert_eq!(
+            no_options.apply_parameters(&Parameters::new()).unwrap_err(),
+            "Choice parameter 'environment' has no options"
+        );
+    }
+}
@@ -0,0 +1,16 @@
+use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+
+/// What is known about a stored secret, without its value.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SecretInfo {
+    pub target: String,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PutSecretRequest {
+    pub value: String,
+}
@@ -1,10 +1,21 @@
 use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+
+use crate::models::pipeline::Parameters;
+
+/// Settings a target is free to carry, as a JSON object.
+pub type TargetSettings = serde_json::Map<String, serde_json::Value>;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Target {
     pub name: String,
     pub repository: String,
     pub branch: String,
+    /// Targets imported from `targets.json` get the time of the import.
+    #[serde(default = "Utc::now")]
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub settings: TargetSettings,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -18,10 +29,47 @@ pub struct AddTargetRequest {
     pub branch: String,
     #[serde(default)]
     pub name: Option<String>,
+    #[serde(default)]
+    pub settings: TargetSettings,
+}
+
+/// A version of a target's pipeline configuration.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PipelineConfigVersion {
+    pub id: i64,
+    /// Commit the configuration was read from. Unknown for configurations
+    /// stored by earlier versions.
+    pub commit_sha: Option<String>,
+    pub fetched_at: DateTime<Utc>,
+}
+
+/// Changes to a target. Fields that are left out keep their value.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateTargetRequest {
+    #[serde(default)]
+    pub repository: Option<String>,
+    #[serde(default)]
+    pub branch: Option<String>,
+    #[serde(default)]
+    pub settings: Option<TargetSettings>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BuildRequest {
     pub repository: String,
     pub branch: String,
-}
\ No newline at end of file
+}
+
+/// A build of a target. Without `commit` the head of the branch is built.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TriggerRequest {
+    /// Commit to build, full or abbreviated. It has to be on the branch.
+    #[serde(default)]
+    pub commit: Option<String>,
+    /// Branch to build instead of the target's.
+    #[serde(default)]
+    pub branch: Option<String>,
+    /// Values for the parameters the pipeline declares.
+    #[serde(default)]
+    pub parameters: Parameters,
+}
@@ -0,0 +1,63 @@
+use std::fmt;
+use std::str::FromStr;
+use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Worker {
+    pub id: String,
+    /// Where jobs are pushed to. Workers without a URL lease jobs instead.
+    pub url: Option<String>,
+    pub labels: Vec<String>,
+    /// Maximum number of jobs the worker runs at once.
+    pub capacity: i32,
+    pub running_jobs: i32,
+    pub status: WorkerStatus,
+    pub registered_at: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RegisterWorkerRequest {
+    /// Re-registering with a known id updates that worker.
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default)]
+    pub labels: Vec<String>,
+    #[serde(default = "default_capacity")]
+    pub capacity: i32,
+}
+
+fn default_capacity() -> i32 {
+    1
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum WorkerStatus {
+    Online,
+    Offline,
+}
+
+impl fmt::Display for WorkerStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WorkerStatus::Online => write!(f, "online"),
+            WorkerStatus::Offline => write!(f, "offline"),
+        }
+    }
+}
+
+impl FromStr for WorkerStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "online" => Ok(WorkerStatus::Online),
+            "offline" => Ok(WorkerStatus::Offline),
+            other => Err(format!("Unknown worker status '{}'", other)),
+        }
+    }
+}
\ No newline at end of file
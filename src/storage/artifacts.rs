@@ -0,0 +1,167 @@
+//! Artifact contents, stored once per distinct content under the data
+//! directory at `<data dir>/artifacts/sha256/<first two hex digits>/<hash>`.
+//! `job_artifacts` rows point at them by hash, so the same file uploaded by
+//! several jobs is kept once.
+
+use rusqlite::{params, Connection};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tempfile::NamedTempFile;
+
+use crate::db::init::DATABASE_FILE;
+use super::data_dir;
+
+/// Media type of artifacts reported as text.
+pub const TEXT_MEDIA_TYPE: &str = "text/plain; charset=utf-8";
+
+/// Stored artifact content.
+#[derive(Debug, Clone)]
+pub struct Blob {
+    /// Hex-encoded SHA-256 of the content.
+    pub sha256: String,
+    pub size: u64,
+}
+
+fn artifacts_dir() -> PathBuf {
+    data_dir().join("artifacts")
+}
+
+/// Where the content with the given hash is stored.
+pub fn path(sha256: &str) -> PathBuf {
+    artifacts_dir().join("sha256").join(&sha256[..2]).join(sha256)
+}
+
+pub fn open(sha256: &str) -> io::Result<File> {
+    File::open(path(sha256))
+}
+
+/// Writes content to the store as it arrives, hashing it on the way.
+pub struct BlobWriter {
+    file: NamedTempFile,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl BlobWriter {
+    pub fn new() -> io::Result<Self> {
+        // Next to the store, so finishing is a rename on the same filesystem
+        let uploads = artifacts_dir().join("uploads");
+        fs::create_dir_all(&uploads)?;
+
+        Ok(BlobWriter {
+            file: NamedTempFile::new_in(uploads)?,
+            hasher: Sha256::new(),
+            size: 0,
+        })
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data)?;
+        self.hasher.update(data);
+        self.size += data.len() as u64;
+        Ok(())
+    }
+
+    /// Moves the content into the store. Content that is stored already is
+    /// kept as it is. A writer dropped without finishing leaves nothing behind.
+    pub fn finish(mut self) -> io::Result<Blob> {
+        self.file.flush()?;
+        let sha256 = self.hasher.finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+
+        // Content that is stored already is touched instead, so pruning
+        // doesn't take it away before the new artifact record points at it
+        let path = path(&sha256);
+        if path.exists() {
+            File::options().append(true).open(&path)?.set_modified(SystemTime::now())?;
+        } else {
+            fs::create_dir_all(path.parent().unwrap())?;
+            self.file.persist(&path).map_err(|e| e.error)?;
+        }
+
+        Ok(Blob { sha256, size: self.size })
+    }
+}
+
+/// Stores content that is already in memory.
+pub fn store(data: &[u8]) -> io::Result<Blob> {
+    let mut writer = BlobWriter::new()?;
+    writer.write(data)?;
+    writer.finish()
+}
+
+/// Deletes stored content that is not in `referenced` and has not been
+/// written for `grace`, along with uploads left over from a crash. Returns
+/// how many bytes were freed.
+pub fn remove_unreferenced(referenced: &HashSet<String>, grace: Duration) -> io::Result<u64> {
+    let mut freed = 0;
+
+    for dir in read_dir_if_exists(&artifacts_dir().join("sha256"))? {
+        for file in read_dir_if_exists(&dir)? {
+            let name = file.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+            if !referenced.contains(name) {
+                freed += remove_if_stale(&file, grace)?;
+            }
+        }
+    }
+
+    for file in read_dir_if_exists(&artifacts_dir().join("uploads"))? {
+        freed += remove_if_stale(&file, grace)?;
+    }
+
+    Ok(freed)
+}
+
+// Removes a file not written for `grace` and returns its size
+fn remove_if_stale(path: &Path, grace: Duration) -> io::Result<u64> {
+    let metadata = fs::metadata(path)?;
+    if metadata.modified()?.elapsed().unwrap_or_default() < grace {
+        return Ok(0);
+    }
+
+    match fs::remove_file(path) {
+        Ok(()) => Ok(metadata.len()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+fn read_dir_if_exists(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    match fs::read_dir(dir) {
+        Ok(entries) => entries.map(|entry| Ok(entry?.path())).collect(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Moves the content of artifacts saved before the artifact store, kept as
+/// text in `job_artifacts.content`, into the store. Returns how many were
+/// moved.
+pub fn migrate_text_artifacts() -> io::Result<usize> {
+    let conn = Connection::open(DATABASE_FILE).map_err(io::Error::other)?;
+    let mut stmt = conn.prepare(
+        "SELECT id, content FROM job_artifacts WHERE sha256 IS NULL"
+    ).map_err(io::Error::other)?;
+
+    let artifacts = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))
+        .map_err(io::Error::other)?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .map_err(io::Error::other)?;
+
+    for (id, content) in &artifacts {
+        let blob = store(content.as_bytes())?;
+        conn.execute(
+            "UPDATE job_artifacts SET sha256 = ?1, size = ?2, media_type = ?3, content = ''
+             WHERE id = ?4",
+            params![blob.sha256, blob.size, TEXT_MEDIA_TYPE, id],
+        ).map_err(io::Error::other)?;
+    }
+
+    Ok(artifacts.len())
+}
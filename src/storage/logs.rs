@@ -0,0 +1,446 @@
+//! Job logs, stored as append-only chunk files under the data directory.
+//!
+//! A log is split into chunks of up to `CHUNK_SIZE` bytes, each in a file
+//! of its own at `<data dir>/logs/<job id>/<chunk>.log[.gz|.zst]`. The
+//! `log_chunks` table indexes where each chunk starts, in bytes and in
+//! lines, so a range or the tail of a log can be read without touching the
+//! rest of it. The chunk a log is being written to is stored uncompressed
+//! and compressed as a whole once it is full or the job has finished.
+
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::db::init::DATABASE_FILE;
+use super::data_dir;
+
+/// Uncompressed size at which a log continues in a new chunk file.
+const CHUNK_SIZE: u64 = 1024 * 1024;
+
+// Keeps concurrent appends from interleaving chunk files and their index
+static WRITES: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    // Full chunks are compressed as set with `LOG_COMPRESSION`
+    fn configured() -> Self {
+        match std::env::var("LOG_COMPRESSION").as_deref() {
+            Err(_) | Ok("") | Ok("none") => Compression::None,
+            Ok("gzip") => Compression::Gzip,
+            Ok("zstd") => Compression::Zstd,
+            Ok(other) => {
+                eprintln!("Unknown LOG_COMPRESSION '{}', storing logs uncompressed", other);
+                Compression::None
+            }
+        }
+    }
+
+    fn parse(name: &str) -> io::Result<Self> {
+        match name {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            other => Err(io::Error::other(format!("Unknown log compression: {}", other))),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Compression::None => "log",
+            Compression::Gzip => "log.gz",
+            Compression::Zstd => "log.zst",
+        }
+    }
+
+    // Chunks from before they were compressed as a whole hold a frame per
+    // append, and frames concatenate
+    fn encode(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Compression::Zstd => zstd::encode_all(data, 0),
+        }
+    }
+
+    fn decode(self, data: Vec<u8>) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data),
+            Compression::Gzip => {
+                let mut decoded = Vec::new();
+                MultiGzDecoder::new(&data[..]).read_to_end(&mut decoded)?;
+                Ok(decoded)
+            }
+            Compression::Zstd => zstd::decode_all(&data[..]),
+        }
+    }
+}
+
+/// One chunk file of a job's log.
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    job_run_id: String,
+    index: i64,
+    /// Byte offset of the chunk in the log.
+    pub offset: u64,
+    /// Uncompressed length of the chunk.
+    pub length: u64,
+    /// Newlines in the log before the chunk.
+    first_line: u64,
+    /// Newlines in the chunk.
+    line_count: u64,
+    compression: Compression,
+}
+
+impl Chunk {
+    fn path(&self) -> PathBuf {
+        log_dir(&self.job_run_id)
+            .join(format!("{:06}.{}", self.index, self.compression.extension()))
+    }
+
+    fn end(&self) -> u64 {
+        self.offset + self.length
+    }
+
+    /// The chunk's uncompressed contents.
+    pub fn read(&self) -> io::Result<Vec<u8>> {
+        match fs::read(self.path()) {
+            Ok(data) => self.compression.decode(data),
+            // Compressed since the chunk was looked up
+            Err(e) if e.kind() == io::ErrorKind::NotFound && self.compression == Compression::None => {
+                for compression in [Compression::Gzip, Compression::Zstd] {
+                    let sealed = Chunk { compression, ..self.clone() };
+                    if let Ok(data) = fs::read(sealed.path()) {
+                        return compression.decode(data);
+                    }
+                }
+                Err(e)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn log_dir(job_run_id: &str) -> PathBuf {
+    data_dir().join("logs").join(job_run_id)
+}
+
+fn open() -> io::Result<Connection> {
+    Connection::open(DATABASE_FILE).map_err(io::Error::other)
+}
+
+fn chunk_from_row(job_run_id: &str, row: &rusqlite::Row) -> rusqlite::Result<(Chunk, String)> {
+    Ok((
+        Chunk {
+            job_run_id: job_run_id.to_string(),
+            index: row.get(0)?,
+            offset: row.get(1)?,
+            length: row.get(2)?,
+            first_line: row.get(3)?,
+            line_count: row.get(4)?,
+            compression: Compression::None,
+        },
+        row.get(5)?,
+    ))
+}
+
+fn with_compression((chunk, compression): (Chunk, String)) -> io::Result<Chunk> {
+    Ok(Chunk { compression: Compression::parse(&compression)?, ..chunk })
+}
+
+const CHUNK_COLUMNS: &str =
+    "chunk_index, byte_offset, byte_length, first_line, line_count, compression";
+
+/// The chunks of a job's log, in order.
+pub fn chunks(job_run_id: &str) -> io::Result<Vec<Chunk>> {
+    let conn = open()?;
+    let chunks = query_chunks(&conn, job_run_id)?;
+    if !chunks.is_empty() {
+        return Ok(chunks);
+    }
+
+    // Logs written before chunked storage are still in `job_runs.output`
+    let _writes = WRITES.lock().unwrap();
+    let output: Option<String> = conn.query_row(
+        "SELECT output FROM job_runs WHERE id = ?1",
+        params![job_run_id],
+        |row| row.get(0),
+    ).optional().map_err(io::Error::other)?.flatten();
+
+    match output {
+        Some(output) if !output.is_empty() => {
+            append_locked(&conn, job_run_id, output.as_bytes())?;
+            seal_locked(&conn, job_run_id)?;
+            conn.execute(
+                "UPDATE job_runs SET output = NULL WHERE id = ?1",
+                params![job_run_id],
+            ).map_err(io::Error::other)?;
+            query_chunks(&conn, job_run_id)
+        }
+        _ => Ok(chunks),
+    }
+}
+
+fn query_chunks(conn: &Connection, job_run_id: &str) -> io::Result<Vec<Chunk>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM log_chunks WHERE job_run_id = ?1 ORDER BY chunk_index",
+        CHUNK_COLUMNS
+    )).map_err(io::Error::other)?;
+
+    let rows = stmt.query_map(params![job_run_id], |row| chunk_from_row(job_run_id, row))
+        .map_err(io::Error::other)?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .map_err(io::Error::other)?;
+
+    rows.into_iter().map(with_compression).collect()
+}
+
+/// Size of a job's log in bytes.
+pub fn size(job_run_id: &str) -> io::Result<u64> {
+    Ok(chunks(job_run_id)?.last().map_or(0, Chunk::end))
+}
+
+/// Appends text to a job's log and returns the byte offset it starts at.
+pub fn append(job_run_id: &str, text: &str) -> io::Result<u64> {
+    let _writes = WRITES.lock().unwrap();
+    append_locked(&open()?, job_run_id, text.as_bytes())
+}
+
+/// Replaces a job's log with the given text.
+pub fn replace(job_run_id: &str, text: &str) -> io::Result<()> {
+    let _writes = WRITES.lock().unwrap();
+    let conn = open()?;
+    delete_locked(&conn, job_run_id)?;
+    append_locked(&conn, job_run_id, text.as_bytes())?;
+    Ok(())
+}
+
+/// Deletes a job's log and returns how many bytes its files took up.
+pub fn delete(job_run_id: &str) -> io::Result<u64> {
+    let _writes = WRITES.lock().unwrap();
+    delete_locked(&open()?, job_run_id)
+}
+
+fn delete_locked(conn: &Connection, job_run_id: &str) -> io::Result<u64> {
+    conn.execute(
+        "DELETE FROM log_chunks WHERE job_run_id = ?1",
+        params![job_run_id],
+    ).map_err(io::Error::other)?;
+
+    let dir = log_dir(job_run_id);
+    let mut freed = 0;
+    match fs::read_dir(&dir) {
+        Ok(entries) => {
+            for entry in entries {
+                freed += entry?.metadata()?.len();
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    }
+
+    fs::remove_dir_all(dir)?;
+    Ok(freed)
+}
+
+/// Compresses the end of a job's log, once the job has finished and nothing
+/// more is written to it.
+pub fn seal(job_run_id: &str) -> io::Result<()> {
+    let _writes = WRITES.lock().unwrap();
+    seal_locked(&open()?, job_run_id)
+}
+
+fn last_chunk(conn: &Connection, job_run_id: &str) -> io::Result<Option<Chunk>> {
+    conn.query_row(
+        &format!(
+            "SELECT {} FROM log_chunks WHERE job_run_id = ?1
+             ORDER BY chunk_index DESC LIMIT 1",
+            CHUNK_COLUMNS
+        ),
+        params![job_run_id],
+        |row| chunk_from_row(job_run_id, row),
+    ).optional().map_err(io::Error::other)?.map(with_compression).transpose()
+}
+
+fn seal_locked(conn: &Connection, job_run_id: &str) -> io::Result<()> {
+    match last_chunk(conn, job_run_id)? {
+        Some(chunk) => compress_chunk(conn, &chunk).map(drop),
+        None => Ok(()),
+    }
+}
+
+// Rewrites an uncompressed chunk compressed as configured, in one frame.
+// Readers find the compressed file once the index points to it.
+fn compress_chunk(conn: &Connection, chunk: &Chunk) -> io::Result<Chunk> {
+    let compression = Compression::configured();
+    if chunk.compression != Compression::None || compression == Compression::None {
+        return Ok(chunk.clone());
+    }
+    rewrite_chunk(conn, chunk, compression)
+}
+
+fn rewrite_chunk(conn: &Connection, chunk: &Chunk, compression: Compression) -> io::Result<Chunk> {
+    let rewritten = Chunk { compression, ..chunk.clone() };
+    let mut temporary = rewritten.path().into_os_string();
+    temporary.push(".tmp");
+    fs::write(&temporary, compression.encode(&chunk.read()?)?)?;
+    fs::rename(&temporary, rewritten.path())?;
+
+    conn.execute(
+        "UPDATE log_chunks SET compression = ?1 WHERE job_run_id = ?2 AND chunk_index = ?3",
+        params![compression.name(), chunk.job_run_id, chunk.index],
+    ).map_err(io::Error::other)?;
+    fs::remove_file(chunk.path())?;
+    Ok(rewritten)
+}
+
+fn append_locked(conn: &Connection, job_run_id: &str, mut data: &[u8]) -> io::Result<u64> {
+    let last = last_chunk(conn, job_run_id)?;
+    let start = last.as_ref().map_or(0, Chunk::end);
+    let mut current = last;
+
+    while !data.is_empty() {
+        let chunk = match current.take() {
+            // A log written to after its job finished continues uncompressed
+            Some(chunk) if chunk.length < CHUNK_SIZE && chunk.compression != Compression::None => {
+                rewrite_chunk(conn, &chunk, Compression::None)?
+            }
+            Some(chunk) if chunk.length < CHUNK_SIZE => chunk,
+            previous => {
+                if let Some(previous) = &previous {
+                    compress_chunk(conn, previous)?;
+                }
+                let chunk = Chunk {
+                    job_run_id: job_run_id.to_string(),
+                    index: previous.as_ref().map_or(0, |chunk| chunk.index + 1),
+                    offset: previous.as_ref().map_or(0, Chunk::end),
+                    length: 0,
+                    first_line: previous.as_ref()
+                        .map_or(0, |chunk| chunk.first_line + chunk.line_count),
+                    line_count: 0,
+                    compression: Compression::None,
+                };
+                fs::create_dir_all(log_dir(job_run_id))?;
+                conn.execute(
+                    "INSERT INTO log_chunks (
+                        job_run_id, chunk_index, byte_offset, byte_length,
+                        first_line, line_count, compression
+                    ) VALUES (?1, ?2, ?3, 0, ?4, 0, ?5)",
+                    params![
+                        job_run_id,
+                        chunk.index,
+                        chunk.offset,
+                        chunk.first_line,
+                        chunk.compression.name()
+                    ],
+                ).map_err(io::Error::other)?;
+                chunk
+            }
+        };
+
+        let room = (CHUNK_SIZE - chunk.length) as usize;
+        let (part, rest) = data.split_at(room.min(data.len()));
+        let lines = part.iter().filter(|&&byte| byte == b'\n').count() as u64;
+
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(chunk.path())?
+            .write_all(part)?;
+
+        conn.execute(
+            "UPDATE log_chunks SET byte_length = byte_length + ?1, line_count = line_count + ?2
+             WHERE job_run_id = ?3 AND chunk_index = ?4",
+            params![part.len() as u64, lines, job_run_id, chunk.index],
+        ).map_err(io::Error::other)?;
+
+        current = Some(Chunk {
+            length: chunk.length + part.len() as u64,
+            line_count: chunk.line_count + lines,
+            ..chunk
+        });
+        data = rest;
+    }
+
+    Ok(start)
+}
+
+/// Bytes `start..end` of a job's log, or as much of them as there is.
+pub fn read(job_run_id: &str, start: u64, end: u64) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    for chunk in chunks(job_run_id)? {
+        if let Some(part) = read_part(&chunk, start, end)? {
+            data.extend_from_slice(&part);
+        }
+    }
+    Ok(data)
+}
+
+/// The part of a chunk that falls in `start..end`, if any does.
+pub fn read_part(chunk: &Chunk, start: u64, end: u64) -> io::Result<Option<Vec<u8>>> {
+    if chunk.end() <= start || chunk.offset >= end {
+        return Ok(None);
+    }
+
+    let mut data = chunk.read()?;
+    let to = ((end - chunk.offset) as usize).min(data.len());
+    let from = (start.saturating_sub(chunk.offset) as usize).min(to);
+    data.truncate(to);
+    data.drain(..from);
+    Ok(Some(data))
+}
+
+/// Byte offset the last `lines` lines of a job's log start at.
+pub fn tail_offset(chunks: &[Chunk], lines: u64) -> io::Result<u64> {
+    let Some(last) = chunks.last() else {
+        return Ok(0);
+    };
+
+    // A last line without a newline still counts
+    let newlines = last.first_line + last.line_count;
+    let total = match last.read()?.last() {
+        Some(b'\n') | None => newlines,
+        Some(_) => newlines + 1,
+    };
+    if lines >= total {
+        return Ok(0);
+    }
+
+    // The tail starts right after the `skip`th newline
+    let skip = total - lines;
+    let Some(chunk) = chunks.iter()
+        .find(|chunk| chunk.first_line < skip && skip <= chunk.first_line + chunk.line_count)
+    else {
+        return Ok(last.end());
+    };
+
+    let position = chunk.read()?
+        .iter()
+        .enumerate()
+        .filter(|(_, byte)| **byte == b'\n')
+        .nth((skip - chunk.first_line - 1) as usize)
+        .map_or(chunk.length, |(position, _)| position as u64 + 1);
+
+    Ok(chunk.offset + position)
+}
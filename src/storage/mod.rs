@@ -0,0 +1,10 @@
+pub mod artifacts;
+pub mod logs;
+pub mod retention;
+
+use std::path::PathBuf;
+
+/// Directory that job logs and artifacts are kept in, set with `DATA_DIR`.
+pub fn data_dir() -> PathBuf {
+    PathBuf::from(std::env::var("DATA_DIR").unwrap_or_else(|_| "data".to_string()))
+}
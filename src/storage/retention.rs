@@ -0,0 +1,170 @@
+//! Pruning of old runs, logs and artifacts.
+//!
+//! Runs are deleted with their jobs and logs once they fall outside the
+//! newest `RETENTION_KEEP_RUNS` of their target, or of their repository and
+//! branch if they have no target, or are older than `RETENTION_RUN_AGE`. Artifacts usually go sooner, after
+//! `RETENTION_ARTIFACT_AGE`, while their run and its logs stay. Pinned and
+//! unfinished runs are always kept. Without any of these settings only
+//! artifact content that nothing refers to any more is deleted.
+
+use chrono::Utc;
+use std::fmt;
+use std::time::Duration;
+
+use crate::db::operations::{
+    delete_artifacts_before,
+    delete_pipeline_run,
+    get_artifact_hashes,
+    get_expired_pipeline_runs,
+};
+use crate::utils::duration;
+use super::{artifacts, logs};
+
+// How long new artifact content is kept before any record points at it
+const UPLOAD_GRACE: Duration = Duration::from_secs(60 * 60);
+
+/// Which runs and artifacts to keep.
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    /// Runs to keep per target, or per repository and branch for runs
+    /// without a target.
+    pub keep_runs: Option<usize>,
+    /// How long runs and their logs are kept.
+    pub run_age: Option<Duration>,
+    /// How long artifacts are kept.
+    pub artifact_age: Option<Duration>,
+}
+
+impl Policy {
+    pub fn from_env() -> Self {
+        Policy {
+            keep_runs: std::env::var("RETENTION_KEEP_RUNS").ok().and_then(|value| {
+                value.parse()
+                    .map_err(|_| eprintln!("Ignoring RETENTION_KEEP_RUNS: invalid number '{}'", value))
+                    .ok()
+            }),
+            run_age: age_from_env("RETENTION_RUN_AGE"),
+            artifact_age: age_from_env("RETENTION_ARTIFACT_AGE"),
+        }
+    }
+}
+
+fn age_from_env(var: &str) -> Option<Duration> {
+    let value = std::env::var(var).ok()?;
+    duration::parse(&value)
+        .map_err(|e| eprintln!("Ignoring {}: {}", var, e))
+        .ok()
+}
+
+/// What one pass of pruning deleted.
+#[derive(Debug, Default)]
+pub struct Pruned {
+    pub runs: usize,
+    pub artifacts: usize,
+    /// Bytes of log and artifact files removed.
+    pub bytes: u64,
+}
+
+impl fmt::Display for Pruned {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} runs and {} artifacts, freeing {}",
+            self.runs,
+            self.artifacts,
+            format_size(self.bytes)
+        )
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} bytes", bytes);
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Deletes whatever the policy no longer keeps.
+pub fn prune(policy: &Policy) -> Result<Pruned, String> {
+    let mut pruned = Pruned::default();
+    let now = Utc::now();
+    let cutoff = |age: Duration| now - chrono::Duration::from_std(age).unwrap_or(chrono::Duration::MAX);
+
+    let runs = get_expired_pipeline_runs(policy.keep_runs, policy.run_age.map(cutoff))
+        .map_err(|e| format!("Failed to find expired runs: {}", e))?;
+    pruned.runs = runs.len();
+    pruned.bytes += delete_runs(&runs)?;
+
+    if let Some(age) = policy.artifact_age {
+        pruned.artifacts = delete_artifacts_before(cutoff(age))
+            .map_err(|e| format!("Failed to delete expired artifacts: {}", e))?;
+    }
+
+    pruned.bytes += remove_unused_content()?;
+
+    Ok(pruned)
+}
+
+/// Deletes finished runs with their jobs, logs and artifact records, and
+/// returns how many bytes of logs that freed. Artifact content is left for
+/// `remove_unused_content`.
+pub fn delete_runs(run_ids: &[String]) -> Result<u64, String> {
+    let mut freed = 0;
+    for run_id in run_ids {
+        let job_ids = delete_pipeline_run(run_id)
+            .map_err(|e| format!("Failed to delete run {}: {}", run_id, e))?;
+        freed += delete_logs(&job_ids)?;
+    }
+    Ok(freed)
+}
+
+/// Deletes the logs of jobs whose records are gone, and returns how many
+/// bytes that freed.
+pub fn delete_logs(job_ids: &[String]) -> Result<u64, String> {
+    let mut freed = 0;
+    for job_id in job_ids {
+        freed += logs::delete(job_id)
+            .map_err(|e| format!("Failed to delete log of job {}: {}", job_id, e))?;
+    }
+    Ok(freed)
+}
+
+/// Deletes artifact content no artifact refers to any more. Content is
+/// shared between artifacts, so it can only go once all of them have.
+pub fn remove_unused_content() -> Result<u64, String> {
+    let referenced = get_artifact_hashes()
+        .map_err(|e| format!("Failed to list artifacts: {}", e))?;
+    artifacts::remove_unreferenced(&referenced, UPLOAD_GRACE)
+        .map_err(|e| format!("Failed to delete artifact content: {}", e))
+}
+
+/// Prunes every `PRUNE_INTERVAL` (1 hour by default), starting right away.
+pub async fn prune_periodically() {
+    let policy = Policy::from_env();
+    let interval = duration::from_env("PRUNE_INTERVAL", Duration::from_secs(60 * 60));
+
+    loop {
+        let pass = {
+            let policy = policy.clone();
+            tokio::task::spawn_blocking(move || prune(&policy)).await
+                .unwrap_or_else(|e| Err(e.to_string()))
+        };
+        match pass {
+            Ok(pruned) if pruned.runs > 0 || pruned.artifacts > 0 || pruned.bytes > 0 => {
+                println!("Pruned {}", pruned);
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Pruning failed: {}", e),
+        }
+
+        tokio::time::sleep(interval).await;
+    }
+}
@@ -0,0 +1,169 @@
+use serde::{Deserialize, Deserializer, Serializer};
+use std::time::Duration;
+
+/// Parses durations such as `45s`, `30m`, `2h` or `1h30m`. A bare number
+/// is a number of seconds.
+pub fn parse(text: &str) -> Result<Duration, String> {
+    let text = text.trim();
+    if let Ok(seconds) = text.parse::<u64>() {
+        return Ok(Duration::from_secs(seconds));
+    }
+
+    let mut total = 0u64;
+    let mut digits = String::new();
+    for c in text.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return Err(format!("Invalid duration '{}'", text)),
+        };
+        let value: u64 = digits.parse()
+            .map_err(|_| format!("Invalid duration '{}'", text))?;
+        total = value.checked_mul(unit)
+            .and_then(|seconds| total.checked_add(seconds))
+            .ok_or_else(|| format!("Duration '{}' is too long", text))?;
+        digits.clear();
+    }
+
+    if !digits.is_empty() || text.is_empty() {
+        return Err(format!("Invalid duration '{}'", text));
+    }
+
+    Ok(Duration::from_secs(total))
+}
+
+/// Reads a timeout or interval from the environment variable `var`, falling
+/// back to `default` when it is unset, invalid or zero.
+pub fn from_env(var: &str, default: Duration) -> Duration {
+    match std::env::var(var).ok().as_deref().map(parse) {
+        Some(Ok(duration)) if duration.is_zero() => {
+            eprintln!("Ignoring {}: it must be longer than zero", var);
+            default
+        }
+        Some(Ok(duration)) => duration,
+        Some(Err(e)) => {
+            eprintln!("Ignoring {}: {}", var, e);
+            default
+        }
+        None => default,
+    }
+}
+
+/// Formats a duration the way `parse` reads it, e.g. `90s`.
+pub fn format(duration: &Duration) -> String {
+    format!("{}s", duration.as_secs())
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Raw {
+    Seconds(u64),
+    Text(String),
+}
+
+impl Raw {
+    fn into_duration(self) -> Result<Duration, String> {
+        match self {
+            Raw::Seconds(seconds) => Ok(Duration::from_secs(seconds)),
+            Raw::Text(text) => parse(&text),
+        }
+    }
+}
+
+/// Serde helpers for `Duration` fields, for use with `#[serde(with = "...")]`.
+pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format(duration))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    Raw::deserialize(deserializer)?
+        .into_duration()
+        .map_err(serde::de::Error::custom)
+}
+
+/// The same helpers for `Option<Duration>` fields.
+pub mod option {
+    use super::Raw;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(
+        duration: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match duration {
+            Some(duration) => serializer.serialize_some(&super::format(duration)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Option::<Raw>::deserialize(deserializer)?
+            .map(Raw::into_duration)
+            .transpose()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// The `option` helpers for timeouts, which can't be zero.
+pub mod timeout {
+    use serde::Deserializer;
+    use std::time::Duration;
+
+    pub use super::option::serialize;
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        match super::option::deserialize(deserializer)? {
+            Some(duration) if duration.is_zero() => {
+                Err(serde::de::Error::custom("timeout must be longer than zero"))
+            }
+            duration => Ok(duration),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_seconds_and_units() {
+        assert_eq!(parse("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse("45s"), Ok(Duration::from_secs(45)));
+        assert_eq!(parse("30m"), Ok(Duration::from_secs(30 * 60)));
+        assert_eq!(parse("2h"), Ok(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(parse("1d"), Ok(Duration::from_secs(24 * 60 * 60)));
+        assert_eq!(parse(" 1h30m "), Ok(Duration::from_secs(90 * 60)));
+        assert_eq!(parse("0s"), Ok(Duration::ZERO));
+    }
+
+    #[test]
+    fn rejects_malformed_durations() {
+        for text in ["", "s", "5x", "1h30", "1.5h", "-5s", "h1"] {
+            assert!(parse(text).is_err(), "'{}' should not parse", text);
+        }
+    }
+
+    #[test]
+    fn rejects_durations_that_overflow() {
+        assert_eq!(parse("300000000000000000d"), Err("Duration '300000000000000000d' is too long".to_string()));
+        assert!(parse("99999999999999999999s").is_err());
+    }
+
+    #[test]
+    fn formats_what_it_parses() {
+        let duration = Duration::from_secs(5400);
+        assert_eq!(parse(&format(&duration)), Ok(duration));
+    }
+}
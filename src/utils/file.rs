@@ -8,7 +8,7 @@ pub fn extract_repo_name(repository: &str) -> String {
     repository
         .trim_end_matches(".git")
         .split('/')
-        .last()
+        .next_back()
         .unwrap_or("unknown")
         .to_string()
 }
@@ -18,28 +18,41 @@ pub fn repo_to_filename(repository: &str, branch: &str) -> String {
     format!("{}_{}.yml", repo_name, branch.replace('/', "_"))
 }
 
+/// Whether `branch` can be passed to git as a branch name rather than
+/// being taken for an option.
+pub fn is_valid_branch(branch: &str) -> bool {
+    !branch.is_empty() && !branch.starts_with('-')
+}
+
+fn check_branch(branch: &str) -> io::Result<()> {
+    if is_valid_branch(branch) {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid branch name '{}'", branch)))
+    }
+}
+
 pub async fn clone_repository(repository: &str, branch: &str) -> io::Result<(TempDir, String)> {
+    check_branch(branch)?;
     let temp_dir = TempDir::new()?;
     let temp_path = temp_dir.path();
 
     let clone_result = Command::new("git")
-        .args(&[
+        .args([
             "clone",
             "--depth", "1",
             "-b", branch,
+            "--",
             repository,
             temp_path.to_str().unwrap()
         ])
         .output()?;
 
     if !clone_result.status.success() {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!(
-                "Failed to clone repository: {}",
-                String::from_utf8_lossy(&clone_result.stderr)
-            ),
-        ));
+        return Err(io::Error::other(format!(
+            "Failed to clone repository: {}",
+            String::from_utf8_lossy(&clone_result.stderr)
+        )));
     }
 
     let config_path = temp_path.join(".pipeline.yml");
@@ -48,20 +61,68 @@ pub async fn clone_repository(repository: &str, branch: &str) -> io::Result<(Tem
     Ok((temp_dir, config_content))
 }
 
-pub fn ensure_directory(path: &str) -> io::Result<()> {
-    if !Path::new(path).exists() {
-        fs::create_dir_all(path)?;
+/// Like `clone_repository`, but checks out `commit`, which has to be in the
+/// history of `branch`, instead of the head of the branch.
+pub async fn clone_commit(repository: &str, branch: &str, commit: &str) -> io::Result<(TempDir, String)> {
+    check_branch(branch)?;
+    let temp_dir = TempDir::new()?;
+    let temp_path = temp_dir.path();
+
+    let clone_result = Command::new("git")
+        .args([
+            "clone",
+            "--single-branch",
+            "-b", branch,
+            "--",
+            repository,
+            temp_path.to_str().unwrap()
+        ])
+        .output()?;
+
+    if !clone_result.status.success() {
+        return Err(io::Error::other(format!(
+            "Failed to clone repository: {}",
+            String::from_utf8_lossy(&clone_result.stderr)
+        )));
     }
-    Ok(())
-}
 
-pub fn save_file(path: &str, content: &str) -> io::Result<()> {
-    if let Some(parent) = Path::new(path).parent() {
-        ensure_directory(parent.to_str().unwrap())?;
+    let checkout_result = Command::new("git")
+        .arg("-C")
+        .arg(temp_path)
+        .args(["checkout", "--quiet", "--detach", commit, "--"])
+        .output()?;
+
+    if !checkout_result.status.success() {
+        return Err(io::Error::other(format!(
+            "Failed to check out commit {}: {}",
+            commit,
+            String::from_utf8_lossy(&checkout_result.stderr)
+        )));
     }
-    fs::write(path, content)
+
+    let config_path = temp_path.join(".pipeline.yml");
+    let config_content = fs::read_to_string(&config_path)?;
+
+    Ok((temp_dir, config_content))
 }
 
 pub fn read_file(path: &str) -> io::Result<String> {
     fs::read_to_string(path)
-}
\ No newline at end of file
+}
+
+pub fn head_commit(repo_path: &Path) -> io::Result<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repo_path)
+        .args(["rev-parse", "HEAD"])
+        .output()?;
+
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "Failed to read commit: {}",
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
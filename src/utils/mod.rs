@@ -1 +1,4 @@
-pub mod file;
\ No newline at end of file
+pub mod duration;
+pub mod file;
+pub mod template;
+pub mod secrets;
\ No newline at end of file
@@ -0,0 +1,311 @@
+use aes_gcm::aead::{Aead, AeadCore, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+
+use crate::db::operations::get_target_secret;
+use crate::models::pipeline::Job;
+use crate::utils::template;
+
+// What secret values are replaced with in job logs
+const MASK: &str = "***";
+
+// Characters URL encoders leave as they are
+const URL_COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+// What to mask in each job's log, by `job_runs` id
+static MASKED: LazyLock<Mutex<HashMap<String, Masked>>> = LazyLock::new(Default::default);
+
+struct Masked {
+    // Texts to mask, longest first
+    texts: Vec<String>,
+    // The end of the streamed log so far, held back since the next chunk
+    // may complete a secret it starts
+    pending: String,
+}
+
+/// Fills in the secrets a job uses, from `${{ secrets.NAME }}` in its
+/// commands and inputs and from inputs with `from_secret`. Secrets come from
+/// the target the run belongs to, or else from the master's `SECRET_<NAME>`
+/// environment variable. Runs without a target get no secrets, since anyone
+/// who can trigger a build could read them. Returns the job to send to a
+/// worker along with the values to mask in its log.
+pub fn resolve(job: &Job, target: Option<&str>) -> Result<(Job, Vec<String>), String> {
+    let mut job = job.clone();
+    let mut values = Vec::new();
+
+    for command in &mut job.commands {
+        *command = render(command, target, &mut values)?;
+    }
+
+    for input in &mut job.inputs {
+        if let Some(name) = &input.from_secret {
+            input.value = lookup(name, target)?
+                .ok_or_else(|| format!("Secret '{}' for input '{}' is not set", name, input.name))?;
+            values.push(input.value.clone());
+        } else {
+            input.value = render(&input.value, target, &mut values)?;
+            if input.secret {
+                values.push(input.value.clone());
+            }
+        }
+    }
+
+    Ok((job, values))
+}
+
+fn render(text: &str, target: Option<&str>, values: &mut Vec<String>) -> Result<String, String> {
+    let names = template::references(text, "secrets");
+    if names.is_empty() {
+        return Ok(text.to_string());
+    }
+
+    let mut secrets = Vec::new();
+    for name in names {
+        let value = lookup(&name, target)?
+            .ok_or_else(|| format!("Secret '{}' is not set", name))?;
+        values.push(value.clone());
+        secrets.push((name, value));
+    }
+    Ok(template::render(text, "secrets", &secrets))
+}
+
+fn lookup(name: &str, target: Option<&str>) -> Result<Option<String>, String> {
+    let Some(target) = target else {
+        return Ok(None);
+    };
+
+    let stored = get_target_secret(target, name)
+        .map_err(|e| format!("Failed to read secret '{}': {}", name, e))?;
+    match stored {
+        Some((nonce, value)) => decrypt(target, name, &nonce, &value).map(Some),
+        None => Ok(std::env::var(format!("SECRET_{}", name)).ok()),
+    }
+}
+
+// The master key, base64 encoded in `SECRETS_KEY` or in the file named by
+// `SECRETS_KEY_FILE`
+fn cipher() -> Result<Aes256Gcm, String> {
+    let encoded = match std::env::var("SECRETS_KEY") {
+        Ok(key) => key,
+        Err(_) => {
+            let path = std::env::var("SECRETS_KEY_FILE")
+                .map_err(|_| "No secrets key is configured, set SECRETS_KEY or SECRETS_KEY_FILE".to_string())?;
+            std::fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read secrets key file {}: {}", path, e))?
+        }
+    };
+
+    let key = STANDARD.decode(encoded.trim())
+        .map_err(|e| format!("Invalid secrets key: {}", e))?;
+    Aes256Gcm::new_from_slice(&key)
+        .map_err(|_| "Invalid secrets key: it must be 32 bytes".to_string())
+}
+
+// Ties a ciphertext to the target and name it is stored under, so it can't
+// be moved to another
+fn associated_data(target: &str, name: &str) -> Vec<u8> {
+    format!("{}\0{}", target, name).into_bytes()
+}
+
+/// Encrypts a target's secret for storage. Returns the nonce and the
+/// encrypted value.
+pub fn encrypt(target: &str, name: &str, value: &str) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let encrypted = cipher()?
+        .encrypt(&nonce, Payload { msg: value.as_bytes(), aad: &associated_data(target, name) })
+        .map_err(|_| format!("Failed to encrypt secret '{}'", name))?;
+
+    Ok((nonce.to_vec(), encrypted))
+}
+
+fn decrypt(target: &str, name: &str, nonce: &[u8], value: &[u8]) -> Result<String, String> {
+    if nonce.len() != 12 {
+        return Err(format!("Secret '{}' is corrupted", name));
+    }
+    let decrypted = cipher()?
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: value, aad: &associated_data(target, name) })
+        .map_err(|_| format!("Failed to decrypt secret '{}', was the secrets key changed?", name))?;
+
+    String::from_utf8(decrypted).map_err(|_| format!("Secret '{}' is corrupted", name))
+}
+
+/// Keeps secret values out of a job's log until dropped.
+pub struct Masking(String);
+
+impl Masking {
+    pub fn new(job_run_id: &str, values: &[String]) -> Self {
+        let mut texts: Vec<String> = values.iter()
+            .flat_map(|value| encodings(value))
+            .filter(|text| !text.is_empty())
+            .collect();
+        // Longer texts first, so no part of one is left over after a
+        // shorter one inside it was replaced
+        texts.sort_by_key(|text| std::cmp::Reverse(text.len()));
+        texts.dedup();
+
+        if !texts.is_empty() {
+            let pending = String::new();
+            MASKED.lock().unwrap().insert(job_run_id.to_string(), Masked { texts, pending });
+        }
+        Masking(job_run_id.to_string())
+    }
+}
+
+impl Drop for Masking {
+    fn drop(&mut self) {
+        MASKED.lock().unwrap().remove(&self.0);
+    }
+}
+
+// A secret as it may show up in a log: as is, base64 or URL encoded, and
+// line by line if it spans several lines
+fn encodings(value: &str) -> Vec<String> {
+    let mut texts = vec![
+        value.to_string(),
+        // Unpadded, so padded forms have their padding left but still match
+        STANDARD_NO_PAD.encode(value),
+        URL_SAFE_NO_PAD.encode(value),
+        utf8_percent_encode(value, URL_COMPONENT).to_string(),
+    ];
+    if value.contains('\n') {
+        texts.extend(value.lines().map(str::trim).map(String::from));
+    }
+    texts
+}
+
+/// Replaces the secret values of a job found in `text` with `***`.
+pub fn mask<'a>(job_run_id: &str, text: &'a str) -> Cow<'a, str> {
+    match MASKED.lock().unwrap().get(job_run_id) {
+        Some(masked) => replace_secrets(&masked.texts, text),
+        None => Cow::Borrowed(text),
+    }
+}
+
+/// Masks a chunk of a job's streamed log. A secret may be split across
+/// chunks, so the end of the log that could be the start of one is held
+/// back until the next chunk or `flush`. Returns the part of the log that
+/// is ready.
+pub fn mask_chunk(job_run_id: &str, chunk: &str) -> String {
+    let mut masked = MASKED.lock().unwrap();
+    let Some(Masked { texts, pending }) = masked.get_mut(job_run_id) else {
+        return chunk.to_string();
+    };
+    let text = std::mem::take(pending) + chunk;
+
+    // Nothing further back than the longest secret can be unfinished
+    let mut split = text.len().saturating_sub(texts[0].len() - 1);
+    loop {
+        split = (split..=text.len())
+            .filter(|&index| text.is_char_boundary(index))
+            .find(|&index| texts.iter().any(|secret| secret.starts_with(&text[index..])))
+            .unwrap_or(text.len());
+
+        // A secret found across the split is masked as a whole
+        let across = texts.iter()
+            .flat_map(|secret| text.match_indices(secret.as_str()))
+            .map(|(start, secret)| (start, start + secret.len()))
+            .filter(|&(start, end)| start < split && split < end)
+            .map(|(_, end)| end)
+            .max();
+        match across {
+            Some(end) => split = end,
+            None => break,
+        }
+    }
+
+    *pending = text[split..].to_string();
+    replace_secrets(texts, &text[..split]).into_owned()
+}
+
+/// Returns what `mask_chunk` held back of a job's log, masked. Called once
+/// the job has finished, before its `Masking` is dropped.
+pub fn flush(job_run_id: &str) -> String {
+    match MASKED.lock().unwrap().get_mut(job_run_id) {
+        Some(masked) => {
+            let pending = std::mem::take(&mut masked.pending);
+            replace_secrets(&masked.texts, &pending).into_owned()
+        }
+        None => String::new(),
+    }
+}
+
+fn replace_secrets<'a>(texts: &[String], text: &'a str) -> Cow<'a, str> {
+    let mut text = Cow::Borrowed(text);
+    for secret in texts {
+        if text.contains(secret.as_str()) {
+            text = Cow::Owned(text.replace(secret.as_str(), MASK));
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masks_secret_values_while_masking_is_held() {
+        let masking = Masking::new("job-plain", &["hunter2".to_string()]);
+        assert_eq!(mask("job-plain", "password: hunter2"), "password: ***");
+        assert_eq!(mask("job-other", "password: hunter2"), "password: hunter2");
+
+        drop(masking);
+        assert_eq!(mask("job-plain", "password: hunter2"), "password: hunter2");
+    }
+
+    #[test]
+    fn masks_base64_encoded_secrets() {
+        let _masking = Masking::new("job-base64", &["hunter22".to_string(), "??>>".to_string()]);
+        // Padding is left over since the unpadded form is what gets masked
+        assert_eq!(mask("job-base64", "token aHVudGVyMjI= end"), "token ***= end");
+        assert_eq!(mask("job-base64", "std Pz8+Pg==, url Pz8-Pg"), "std ***==, url ***");
+    }
+
+    #[test]
+    fn masks_url_encoded_secrets() {
+        let _masking = Masking::new("job-url", &["p@ss word/1".to_string()]);
+        assert_eq!(
+            mask("job-url", "GET /login?password=p%40ss%20word%2F1 HTTP/1.1"),
+            "GET /login?password=*** HTTP/1.1"
+        );
+    }
+
+    #[test]
+    fn masks_each_line_of_a_multiline_secret() {
+        let key = "-----BEGIN KEY-----\nc2VjcmV0\n-----END KEY-----".to_string();
+        let _masking = Masking::new("job-lines", &[key]);
+        assert_eq!(mask("job-lines", "key line: c2VjcmV0"), "key line: ***");
+    }
+
+    #[test]
+    fn masks_longer_secrets_before_ones_inside_them() {
+        let _masking = Masking::new("job-overlap", &["abc".to_string(), "abcdef".to_string()]);
+        assert_eq!(mask("job-overlap", "abcdef abc"), "*** ***");
+    }
+
+    #[test]
+    fn masks_secrets_split_across_log_chunks() {
+        let _masking = Masking::new("job-chunks", &["hunter22".to_string()]);
+        assert_eq!(mask_chunk("job-chunks", "password: hun"), "password: ");
+        assert_eq!(mask_chunk("job-chunks", "ter22 and token aHVudG"), "*** and token ");
+        assert_eq!(mask_chunk("job-chunks", "VyMjI=\nhunt"), "***=\n");
+        assert_eq!(flush("job-chunks"), "hunt");
+        assert_eq!(flush("job-chunks"), "");
+    }
+
+    #[test]
+    fn passes_on_log_chunks_that_cannot_start_a_secret() {
+        let _masking = Masking::new("job-chunks-clear", &["hunter22".to_string()]);
+        assert_eq!(mask_chunk("job-chunks-clear", "line 1\n"), "line 1\n");
+        assert_eq!(mask_chunk("job-other-chunks", "hun"), "hun");
+    }
+}
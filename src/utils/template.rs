@@ -0,0 +1,49 @@
+/// Replaces `${{ scope.key }}` placeholders in `text` with the matching
+/// value. Placeholders for other scopes or unknown keys are left untouched.
+pub fn render(text: &str, scope: &str, values: &[(String, String)]) -> String {
+    let mut rendered = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find("${{") {
+        let Some(length) = rest[start..].find("}}") else {
+            break;
+        };
+        let end = start + length + 2;
+        let expression = rest[start + 3..end - 2].trim();
+
+        let value = expression.strip_prefix(scope)
+            .and_then(|key| key.strip_prefix('.'))
+            .and_then(|key| values.iter().find(|(name, _)| name == key))
+            .map(|(_, value)| value.as_str());
+
+        rendered.push_str(&rest[..start]);
+        rendered.push_str(value.unwrap_or(&rest[start..end]));
+        rest = &rest[end..];
+    }
+
+    rendered.push_str(rest);
+    rendered
+}
+
+/// Keys of the `${{ scope.key }}` placeholders in `text`, in order.
+pub fn references(text: &str, scope: &str) -> Vec<String> {
+    let mut keys = Vec::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find("${{") {
+        let Some(length) = rest[start..].find("}}") else {
+            break;
+        };
+        let end = start + length + 2;
+        let key = rest[start + 3..end - 2].trim()
+            .strip_prefix(scope)
+            .and_then(|key| key.strip_prefix('.'));
+
+        if let Some(key) = key {
+            keys.push(key.to_string());
+        }
+        rest = &rest[end..];
+    }
+
+    keys
+}